@@ -0,0 +1,157 @@
+//! Run the host actions the keyboards request, limited to the ones in their config
+
+use std::{collections::HashMap, process::Command, thread};
+
+use anyhow::Context;
+use zbus::{blocking::Connection, zvariant::Value};
+
+use crate::{
+    config::{ActionConfig, Config},
+    connection::SharedConnection,
+    events::BoardEvent,
+    protocol::Message,
+    transport::HidTransport,
+};
+
+/// Object path and interface of the signal emitted for [`ActionConfig::DbusSignal`]
+pub const SIGNAL_PATH: &str = "/io/github/keeb_os_probe";
+pub const SIGNAL_INTERFACE: &str = "io.github.keeb_os_probe.Actions";
+
+/// Listen for the action requests of the keyboards in a background thread
+///
+/// Actions are looked up in the current config, so names removed by a reload are refused.
+/// [`ActionConfig::DbusSignal`] is emitted on `service_bus`, the connection owning
+/// [`BUS_NAME`](crate::dbus::BUS_NAME), so clients can match the signal on its sender.
+pub fn spawn<T: HidTransport + 'static>(
+    connection: SharedConnection<T>,
+    service_bus: Option<Connection>,
+) {
+    let events = connection.lock().events().subscribe();
+    thread::spawn(move || {
+        for event in events {
+            let BoardEvent::Message {
+                keeb,
+                message: Message::Action { name },
+            } = event
+            else {
+                continue;
+            };
+            let action = configured_action(connection.lock().config(), &keeb, &name);
+            let Some(action) = action else {
+                tracing::warn!(
+                    keeb,
+                    action = name,
+                    "Refused an action that isn't configured"
+                );
+                continue;
+            };
+            tracing::info!(keeb, action = name, "Running action");
+            let service_bus = service_bus.clone();
+            // a slow command or bus call doesn't hold up the following requests
+            thread::spawn(move || {
+                if let Err(err) = run(&keeb, &name, &action, service_bus.as_ref()) {
+                    tracing::error!(keeb, action = name, "Action failed: {err:#}");
+                }
+            });
+        }
+    });
+}
+
+/// The action `name` in the keyboard's config, `None` for names the keyboard isn't allowed to run
+fn configured_action(config: &Config, keeb: &str, name: &str) -> Option<ActionConfig> {
+    config
+        .keyboards
+        .get(keeb)
+        .and_then(|keeb_config| keeb_config.actions.get(name))
+        .cloned()
+}
+
+fn run(
+    keeb: &str,
+    name: &str,
+    action: &ActionConfig,
+    service_bus: Option<&Connection>,
+) -> anyhow::Result<()> {
+    match action {
+        ActionConfig::Run { command } => command_status(Command::new("sh").args(["-c", command])),
+        ActionConfig::AudioSink { sink } => {
+            command_status(Command::new("pactl").args(["set-default-sink", sink]))
+        }
+        ActionConfig::LockScreen => command_status(Command::new("loginctl").arg("lock-session")),
+        ActionConfig::Notify { summary, body } => {
+            Connection::session()?.call_method(
+                Some("org.freedesktop.Notifications"),
+                "/org/freedesktop/Notifications",
+                Some("org.freedesktop.Notifications"),
+                "Notify",
+                &(
+                    "keeb_os_probe",
+                    0u32,
+                    "input-keyboard",
+                    summary,
+                    body.as_deref().unwrap_or_default(),
+                    Vec::<&str>::new(),
+                    HashMap::<&str, Value>::new(),
+                    -1i32,
+                ),
+            )?;
+            Ok(())
+        }
+        ActionConfig::DbusSignal => {
+            let bus = service_bus.context("The D-Bus service isn't running")?;
+            bus.emit_signal(
+                None::<&str>,
+                SIGNAL_PATH,
+                SIGNAL_INTERFACE,
+                "Triggered",
+                &(keeb, name),
+            )?;
+            Ok(())
+        }
+    }
+}
+
+fn command_status(command: &mut Command) -> anyhow::Result<()> {
+    let status = command
+        .status()
+        .context(format!("Failed to start {command:?}"))?;
+    if !status.success() {
+        anyhow::bail!("{command:?} exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transport::{MockTransport, MOCK_KEYBOARD},
+        BoardConnection,
+    };
+
+    fn parse(actions: &str) -> Config {
+        toml::from_str(&format!(
+            "{MOCK_KEYBOARD}\n[keyboards.klor.actions]\n{actions}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn refuses_unconfigured_actions() {
+        let config = parse(r#"lock = { type = "lock_screen" }"#);
+        assert!(matches!(
+            configured_action(&config, "klor", "lock"),
+            Some(ActionConfig::LockScreen)
+        ));
+        assert!(configured_action(&config, "klor", "rm").is_none());
+        assert!(configured_action(&config, "other", "lock").is_none());
+    }
+
+    #[test]
+    fn refuses_actions_removed_by_a_reload() {
+        let config = parse(r#"lock = { type = "lock_screen" }"#);
+        let mut connection = BoardConnection::with_transport(MockTransport::new(), config);
+        connection.set_config(parse(r#"notify = { type = "lock_screen" }"#));
+        assert!(configured_action(connection.config(), "klor", "lock").is_none());
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use glob::Pattern;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{host::OsVariant, payload::PayloadTemplate, protocol::PACKET_SIZE};
+
+/// [QMK raw HID](https://docs.qmk.fm/features/rawhid) interface defaults
+const QMK_RAW_HID_USAGE: u16 = 0x61;
+const QMK_RAW_HID_USAGE_PAGE: u16 = 0xFF60;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub keyboards: HashMap<String, KeyboardConfig>,
+    /// Switch keyboard layers based on the focused application
+    pub focus: Option<FocusConfig>,
+    /// System stats sampling, sent to the keyboards with `stats` enabled
+    #[serde(default)]
+    pub stats: StatsConfig,
+    /// Overrides for the detected host OS and descriptors
+    #[serde(default)]
+    pub host: HostConfig,
+    /// Log filter, e.g. `debug`, overridden by `--log-level` and `RUST_LOG`
+    pub log_level: Option<String>,
+    /// Probe retries for keyboards that fail right after being plugged in
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Per host settings, keyed by hostname
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfig>,
+}
+impl Config {
+    /// `keeb_os_probe.toml` in the local config dir
+    pub fn default_path() -> anyhow::Result<PathBuf> {
+        let mut config_path = dirs::config_local_dir().context("Could not find config path")?;
+        config_path.push("keeb_os_probe.toml");
+        Ok(config_path)
+    }
+
+    /// Read, parse and validate the config file
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config_toml = fs::read_to_string(path).context(format!("Config path: {:?}", path))?;
+        let config: Config =
+            toml::from_str(&config_toml).context(format!("Invalid config: {:?}", path))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.keyboards.is_empty() {
+            anyhow::bail!("No boards configured");
+        }
+        for (keeb, keeb_config) in &self.keyboards {
+            if keeb_config.report_size < PACKET_SIZE {
+                anyhow::bail!(
+                    "Keeb '{keeb}' report size {} is smaller than the {PACKET_SIZE} byte protocol packets",
+                    keeb_config.report_size
+                );
+            }
+            if let Some(payload) = &keeb_config.payload {
+                if payload.is_empty() || payload.len() > keeb_config.report_size {
+                    anyhow::bail!(
+                        "Keeb '{keeb}' payload must be 1 to {} bytes long",
+                        keeb_config.report_size
+                    );
+                }
+                if keeb_config.handshake.is_some() {
+                    anyhow::bail!(
+                        "Keeb '{keeb}' can't combine a custom payload with the handshake, the ack needs the framed protocol"
+                    );
+                }
+            }
+            if keeb_config.handshake.is_some() && keeb_config.protocol != Protocol::Framed {
+                anyhow::bail!(
+                    "Keeb '{keeb}' handshake needs `protocol = \"framed\"`, the ack is a framed message"
+                );
+            }
+            for pattern in [
+                &keeb_config.serial_number,
+                &keeb_config.manufacturer,
+                &keeb_config.product,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                Pattern::new(pattern)
+                    .context(format!("Keeb '{keeb}' has an invalid pattern '{pattern}'"))?;
+            }
+        }
+        if let Some((hostname, _)) = self
+            .profiles
+            .iter()
+            .find(|(_, profile)| profile.profile_id != 0)
+        {
+            for (keeb, keeb_config) in &self.keyboards {
+                let sends_profile_id = match &keeb_config.payload {
+                    Some(payload) => payload.has_profile_id(),
+                    None => keeb_config.protocol == Protocol::Framed,
+                };
+                if !sends_profile_id {
+                    anyhow::bail!(
+                        "Keeb '{keeb}' can't receive the profile ID of host '{hostname}', the legacy host report has no room for it, use protocol = \"framed\" or a \"{{profile_id}}\" payload byte"
+                    );
+                }
+            }
+        }
+        let mut hostnames: Vec<_> = self
+            .profiles
+            .keys()
+            .map(|hostname| hostname.to_ascii_lowercase())
+            .collect();
+        hostnames.sort();
+        if let Some(duplicate) = hostnames.windows(2).find(|pair| pair[0] == pair[1]) {
+            anyhow::bail!("Several profiles for host '{}'", duplicate[0]);
+        }
+        if let Some(log_level) = &self.log_level {
+            EnvFilter::try_new(log_level).context(format!("Invalid log level '{log_level}'"))?;
+        }
+        if self.retry.attempts == 0 {
+            anyhow::bail!("Retry attempts must be positive");
+        }
+        if self.retry.max_backoff_ms < self.retry.backoff_ms {
+            anyhow::bail!(
+                "Retry max_backoff_ms ({}) is below backoff_ms ({})",
+                self.retry.max_backoff_ms,
+                self.retry.backoff_ms
+            );
+        }
+        if self.stats.interval_ms == 0 {
+            anyhow::bail!("Stats interval must be positive");
+        }
+        Ok(())
+    }
+
+    /// Profile of the host, hostnames are compared ASCII case insensitively like in [`Self::validate`]
+    pub fn profile(&self, hostname: &str) -> Option<&ProfileConfig> {
+        self.profiles
+            .iter()
+            .find(|(profile_host, _)| profile_host.eq_ignore_ascii_case(hostname))
+            .map(|(_, profile)| profile)
+    }
+
+    /// Apply the feature toggles and default layer of the host profile, returns its ID or 0 without one
+    pub fn apply_profile(&mut self, hostname: &str) -> u8 {
+        let Some(profile) = self.profile(hostname) else {
+            return 0;
+        };
+        let ProfileConfig {
+            profile_id,
+            default_layer,
+            time_sync,
+            host_info,
+            stats,
+            now_playing,
+            layout,
+            volume,
+            session_state,
+            focus,
+        } = *profile;
+        for keeb_config in self.keyboards.values_mut() {
+            match time_sync {
+                Some(true) if keeb_config.time_sync.is_none() => {
+                    keeb_config.time_sync = Some(TimeSyncConfig::default());
+                }
+                Some(false) => keeb_config.time_sync = None,
+                _ => {}
+            }
+            keeb_config.host_info = host_info.unwrap_or(keeb_config.host_info);
+            keeb_config.stats = stats.unwrap_or(keeb_config.stats);
+            keeb_config.now_playing = now_playing.unwrap_or(keeb_config.now_playing);
+            keeb_config.layout = layout.unwrap_or(keeb_config.layout);
+            keeb_config.volume = volume.unwrap_or(keeb_config.volume);
+            keeb_config.session_state = session_state.unwrap_or(keeb_config.session_state);
+        }
+        if focus == Some(false) {
+            self.focus = None;
+        }
+        if let (Some(focus), Some(layer)) = (&mut self.focus, default_layer) {
+            focus.default_layer = Some(layer);
+        }
+        profile_id
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyboardConfig {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Glob patterns matched against the USB device strings,
+    /// e.g. to tell apart two boards with the same vendor & product IDs
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Usage of the raw HID interface
+    #[serde(default = "default_usage")]
+    pub usage: u16,
+    /// Usage page of the raw HID interface
+    #[serde(default = "default_usage_page")]
+    pub usage_page: u16,
+    /// USB interface number of the raw HID interface, for boards exposing several matching ones
+    pub interface_number: Option<i32>,
+    /// Report ID prepended to every report, 0 for interfaces without numbered reports
+    #[serde(default)]
+    pub report_id: u8,
+    /// Report size in bytes, excluding the report ID
+    #[serde(default = "default_report_size")]
+    pub report_size: usize,
+    /// Format of the host OS report, the legacy one unless the firmware speaks the framed protocol
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Custom host report sent instead of the host OS report
+    pub payload: Option<PayloadTemplate>,
+    /// Wait for the board to acknowledge the host report,
+    /// firmware without an ack reply should leave this out
+    pub handshake: Option<HandshakeConfig>,
+    /// Send the host's local time after the OS and then periodically
+    pub time_sync: Option<TimeSyncConfig>,
+    /// Send the distro, desktop environment and hostname after the OS
+    #[serde(default)]
+    pub host_info: bool,
+    /// Stream CPU, memory and temperature stats
+    #[serde(default)]
+    pub stats: bool,
+    /// Forward the title, artist and playback status of MPRIS media players
+    #[serde(default)]
+    pub now_playing: bool,
+    /// Send the active keyboard layout whenever the host switches it
+    #[serde(default)]
+    pub layout: bool,
+    /// Send the default audio output volume and mute state whenever they change
+    #[serde(default)]
+    pub volume: bool,
+    /// Send the screen lock and idle state whenever they change
+    #[serde(default)]
+    pub session_state: bool,
+    /// Host actions the board may request by name, anything else is refused
+    #[serde(default)]
+    pub actions: HashMap<String, ActionConfig>,
+}
+
+/// Host OS report format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// `[42, os_code]`, the report of the original QMK "reporting host" firmware
+    #[default]
+    Legacy,
+    /// [`Message::HostOs`](crate::protocol::Message::HostOs) packet with the protocol header
+    /// and the profile ID, required for the handshake
+    Framed,
+}
+
+/// Host action, e.g. `lock = { type = "lock_screen" }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActionConfig {
+    /// Shell command, run with `sh -c`
+    Run { command: String },
+    /// Switch the PulseAudio / PipeWire default output
+    AudioSink { sink: String },
+    /// Lock the logind session
+    LockScreen,
+    /// Desktop notification
+    Notify {
+        summary: String,
+        body: Option<String>,
+    },
+    /// `io.github.keeb_os_probe.Actions.Triggered(keeb, action)` signal on the session bus
+    DbusSignal,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HandshakeConfig {
+    /// How long to wait for the ack after each write
+    #[serde(default = "default_ack_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How many times to resend the host report when no ack arrives
+    #[serde(default = "default_ack_retries")]
+    pub retries: u8,
+}
+
+/// Every field replaces the detected value, e.g. `os` for a VM or a KVM switch
+#[derive(Debug, Default, Deserialize)]
+pub struct HostConfig {
+    pub os: Option<OsVariant>,
+    pub distro_id: Option<String>,
+    pub desktop: Option<String>,
+    pub hostname: Option<String>,
+}
+
+/// Settings for one of the hosts the keyboards move between, e.g. through a KVM switch
+///
+/// The toggles turn a feature on (`true`) or off (`false`) for all keyboards on this host,
+/// leaving them out keeps the keyboard settings.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ProfileConfig {
+    /// Sent with the host OS so the firmware can load host specific behaviour
+    pub profile_id: u8,
+    /// Layer sent after the host OS, also the `[focus]` fallback layer on this host
+    pub default_layer: Option<u8>,
+    pub time_sync: Option<bool>,
+    pub host_info: Option<bool>,
+    pub stats: Option<bool>,
+    pub now_playing: Option<bool>,
+    pub layout: Option<bool>,
+    pub volume: Option<bool>,
+    pub session_state: Option<bool>,
+    /// Only turns `[focus]` off, there are no rules to turn on
+    pub focus: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfig {
+    /// How long to wait for the HID node to show up after the USB device arrived
+    #[serde(default = "default_ready_timeout_ms")]
+    pub ready_timeout_ms: u64,
+    /// Probe attempts, including the first one
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for every following one
+    #[serde(default = "default_retry_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Cap of the doubled wait, at least `backoff_ms`
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            ready_timeout_ms: default_ready_timeout_ms(),
+            attempts: default_retry_attempts(),
+            backoff_ms: default_retry_backoff_ms(),
+            max_backoff_ms: default_retry_max_backoff_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeSyncConfig {
+    #[serde(default = "default_time_sync_interval_secs")]
+    pub interval_secs: u64,
+}
+impl Default for TimeSyncConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_time_sync_interval_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsConfig {
+    #[serde(default = "default_stats_interval_ms")]
+    pub interval_ms: u64,
+    /// Thermal zone directory name (e.g. `thermal_zone0`) or type (e.g. `x86_pkg_temp`),
+    /// the temperature is left out without it
+    pub thermal_zone: Option<String>,
+}
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: default_stats_interval_ms(),
+            thermal_zone: None,
+        }
+    }
+}
+
+fn default_usage() -> u16 {
+    QMK_RAW_HID_USAGE
+}
+
+fn default_usage_page() -> u16 {
+    QMK_RAW_HID_USAGE_PAGE
+}
+
+fn default_report_size() -> usize {
+    PACKET_SIZE
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    500
+}
+
+fn default_ack_retries() -> u8 {
+    2
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FocusConfig {
+    /// Layer to switch to when no rule matches,
+    /// without it the layer is left as is
+    pub default_layer: Option<u8>,
+    /// Checked in order, the first matching rule wins
+    #[serde(default)]
+    pub rules: Vec<FocusRule>,
+}
+
+/// Switch to `layer` when the focused window matches both `class` and `title`,
+/// matched as case-insensitive substrings, a missing matcher matches anything
+#[derive(Debug, Deserialize)]
+pub struct FocusRule {
+    /// X11 `WM_CLASS` class or Wayland app ID
+    pub class: Option<String>,
+    pub title: Option<String>,
+    pub layer: u8,
+}
+
+fn default_ready_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_retry_attempts() -> u32 {
+    5
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    2000
+}
+
+fn default_time_sync_interval_secs() -> u64 {
+    60
+}
+
+fn default_stats_interval_ms() -> u64 {
+    1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[keyboards.klor]
+vendor_id = 0x3a3c
+product_id = 0x0001
+protocol = "framed"
+stats = true
+time_sync = {}
+
+[focus]
+default_layer = 0
+rules = []
+
+[profiles.Work-Laptop]
+profile_id = 3
+default_layer = 2
+stats = false
+now_playing = true
+time_sync = false
+"#;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn profile_hostnames_ignore_ascii_case() {
+        let config = parse(CONFIG);
+        config.validate().unwrap();
+        assert_eq!(config.profile("work-laptop").unwrap().profile_id, 3);
+        assert!(config.profile("home").is_none());
+
+        let duplicate = parse(&format!(
+            "{CONFIG}\n[profiles.WORK-LAPTOP]\nprofile_id = 4\n"
+        ));
+        let err = duplicate.validate().unwrap_err().to_string();
+        assert!(err.contains("work-laptop"), "{err}");
+
+        // only ASCII is folded, so the lookup can't pick one of these
+        let non_ascii = parse(&format!(
+            "{CONFIG}\n[profiles.\"ÉCOLE\"]\nprofile_id = 4\n[profiles.\"école\"]\nprofile_id = 5\n"
+        ));
+        non_ascii.validate().unwrap();
+        assert_eq!(non_ascii.profile("école").unwrap().profile_id, 5);
+    }
+
+    #[test]
+    fn profile_id_needs_room_in_the_host_report() {
+        let legacy = CONFIG.replace("protocol = \"framed\"\n", "");
+        let err = parse(&legacy).validate().unwrap_err().to_string();
+        assert!(err.contains("profile ID"), "{err}");
+        let payload = legacy.replace(
+            "stats = true",
+            "payload = [\"0x2A\", \"{os_code}\", \"{profile_id}\"]\nstats = true",
+        );
+        parse(&payload).validate().unwrap();
+        parse(&legacy.replace("profile_id = 3", "profile_id = 0"))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn max_backoff_below_backoff() {
+        let config = parse(&format!(
+            "{CONFIG}\n[retry]\nbackoff_ms = 500\nmax_backoff_ms = 100\n"
+        ));
+        assert!(config.validate().is_err());
+        let config = parse(&format!(
+            "{CONFIG}\n[retry]\nbackoff_ms = 500\nmax_backoff_ms = 500\n"
+        ));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn profile_toggles_override_the_keyboards() {
+        let mut config = parse(CONFIG);
+        assert_eq!(config.apply_profile("WORK-LAPTOP"), 3);
+        let klor = &config.keyboards["klor"];
+        assert!(!klor.stats);
+        assert!(klor.now_playing);
+        assert!(klor.time_sync.is_none());
+        // left out toggles keep the keyboard settings
+        assert!(!klor.host_info);
+        assert!(!klor.volume);
+        assert_eq!(config.focus.unwrap().default_layer, Some(2));
+    }
+
+    #[test]
+    fn profile_can_turn_focus_off() {
+        let mut config = parse(&CONFIG.replace("time_sync = false", "focus = false"));
+        config.apply_profile("work-laptop");
+        assert!(config.focus.is_none());
+    }
+
+    #[test]
+    fn no_profile_keeps_the_config() {
+        let mut config = parse(CONFIG);
+        assert_eq!(config.apply_profile("home"), 0);
+        let klor = &config.keyboards["klor"];
+        assert!(klor.stats);
+        assert!(klor.time_sync.is_some());
+        assert_eq!(config.focus.unwrap().default_layer, Some(0));
+    }
+}
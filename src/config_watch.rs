@@ -0,0 +1,83 @@
+//! Reload the config file when it changes
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    thread,
+};
+
+use anyhow::Context;
+use inotify::{Inotify, WatchMask};
+
+use crate::config::Config;
+
+/// Watch the config file in a background thread, sending every valid new version to `reloads`
+///
+/// The parent directory is watched rather than the file itself,
+/// as editors commonly save by replacing the file.
+/// An invalid file is reported and skipped, so the daemon keeps running on the previous config.
+pub fn spawn(path: PathBuf, reloads: Sender<Config>) -> anyhow::Result<()> {
+    let dir = watched_dir(&path)?.to_owned();
+    let file_name = path
+        .file_name()
+        .context(format!("Config path has no file name: {path:?}"))?
+        .to_owned();
+    let mut inotify = Inotify::init()?;
+    inotify.watches().add(
+        &dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(err) => {
+                    tracing::error!("Config watcher stopped: {err}");
+                    return;
+                }
+            };
+            // a single save can emit several events, reload once per batch
+            if !events
+                .into_iter()
+                .any(|event| event.name == Some(&file_name))
+            {
+                continue;
+            }
+            match Config::load(&path) {
+                Ok(config) => {
+                    if reloads.send(config).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => tracing::error!(?path, "Keeping the current config: {err:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The directory containing the config file, a bare file name like `--config keeb.toml`
+/// has an empty parent that stands for the working directory
+fn watched_dir(path: &Path) -> anyhow::Result<&Path> {
+    match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Ok(Path::new(".")),
+        Some(dir) => Ok(dir),
+        None => anyhow::bail!("Config path has no parent: {path:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_file_name_watches_the_working_dir() {
+        assert_eq!(watched_dir(Path::new("keeb.toml")).unwrap(), Path::new("."));
+        assert_eq!(
+            watched_dir(Path::new("/etc/keeb.toml")).unwrap(),
+            Path::new("/etc")
+        );
+        assert!(watched_dir(Path::new("/")).is_err());
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use glob::Pattern;
+
+use crate::{
+    config::{Config, HandshakeConfig, KeyboardConfig, Protocol, RetryConfig},
+    events::{BoardEvent, EventBus},
+    host::HostInfo,
+    protocol::{self, command, Message},
+    registry::{Registry, UsbLocation},
+    session::Session,
+    time_sync,
+    transport::{DeviceInfo, HidApiTransport, HidHandle, HidTransport},
+};
+
+/// How often [`SharedConnection::probe_with_retry`] checks for the HID node of a plugged in keyboard
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct BoardConnection<T: HidTransport> {
+    transport: T,
+    config: Config,
+    host: HostInfo,
+    registry: Registry,
+    /// Open raw HID interfaces by path
+    sessions: HashMap<String, Session<T::Device>>,
+    events: EventBus,
+}
+impl BoardConnection<HidApiTransport> {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        Ok(Self::with_transport(HidApiTransport::new()?, config))
+    }
+}
+impl<T: HidTransport> BoardConnection<T> {
+    pub fn with_transport(transport: T, config: Config) -> Self {
+        let mut config = config;
+        let host = resolve_host(&mut config);
+        Self {
+            transport,
+            config,
+            host,
+            registry: Registry::default(),
+            sessions: HashMap::new(),
+            events: EventBus::default(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Host OS and descriptors sent to the keyboards
+    pub fn host(&self) -> &HostInfo {
+        &self.host
+    }
+
+    /// Swap the config, the keyboards are looked up in the new one from now on
+    ///
+    /// Sessions are only reopened when their keyboard's report settings changed,
+    /// so the readers of the connected keyboards keep running.
+    pub fn set_config(&mut self, mut config: Config) {
+        self.host = resolve_host(&mut config);
+        self.registry
+            .retain(|keeb| config.keyboards.contains_key(keeb));
+        let previous = &self.config;
+        // the reader keeps the report settings it was opened with
+        self.sessions.retain(|_, session| {
+            let keeb = session.keeb();
+            match (previous.keyboards.get(keeb), config.keyboards.get(keeb)) {
+                (Some(previous), Some(current)) => {
+                    previous.report_id == current.report_id
+                        && previous.report_size == current.report_size
+                }
+                _ => false,
+            }
+        });
+        self.config = config;
+        if let Err(err) = self.open_sessions() {
+            tracing::warn!("Failed to reopen the keyboard sessions: {err:#}");
+        }
+    }
+
+    /// Open the interfaces of the connected keyboards that have no session,
+    /// so their board messages keep being read without waiting for the next write
+    fn open_sessions(&mut self) -> anyhow::Result<()> {
+        let devices = self.transport.devices()?;
+        for board in self.registry.boards() {
+            let Some(keeb_config) = self.config.keyboards.get(&board.keeb) else {
+                continue;
+            };
+            for device in devices
+                .iter()
+                .filter(|device| is_raw_hid_interface(device, keeb_config))
+            {
+                let path = device.path.to_string_lossy().into_owned();
+                if !board.paths.contains(&path) || self.sessions.contains_key(&path) {
+                    continue;
+                }
+                match self.transport.open(device) {
+                    Ok(opened) => {
+                        let session = Session::open(
+                            &board.keeb,
+                            path.clone(),
+                            opened,
+                            keeb_config,
+                            self.events.clone(),
+                        );
+                        self.sessions.insert(path, session);
+                    }
+                    Err(err) => {
+                        tracing::warn!(keeb = board.keeb, path, "Failed to reopen: {err:#}")
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keyboard arrivals, departures and the messages they send on their own
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Keyboards probed since they were plugged in
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Forget the keyboards on an unplugged USB device and close their sessions
+    pub fn device_left(&mut self, usb: UsbLocation) {
+        for board in self.registry.left(usb) {
+            self.sessions.retain(|path, _| !board.paths.contains(path));
+            tracing::info!(keeb = board.keeb, usb.bus, usb.address, "Disconnected");
+            self.events
+                .publish(BoardEvent::Disconnected { keeb: board.keeb });
+        }
+    }
+
+    /// Configured keyboards with the vendor & product IDs of a USB device
+    pub fn keebs_matching(&self, vendor_id: u16, product_id: u16) -> Vec<String> {
+        self.config
+            .keyboards
+            .iter()
+            .filter(|(_, keeb_config)| {
+                keeb_config.vendor_id == vendor_id && keeb_config.product_id == product_id
+            })
+            .map(|(keeb, _)| keeb.clone())
+            .collect()
+    }
+
+    /// Paths of the enumerated raw HID interfaces of the keyboard on the USB device, without opening them
+    ///
+    /// Interfaces the transport can't place on a USB device are included,
+    /// identical boards on other USB devices are not.
+    pub fn device_paths(&mut self, keeb: &str, usb: UsbLocation) -> anyhow::Result<Vec<String>> {
+        let keeb_config = self
+            .config
+            .keyboards
+            .get(keeb)
+            .context(format!("Unknown keeb '{keeb}'"))?;
+        Ok(self
+            .transport
+            .devices()?
+            .iter()
+            .filter(|device| is_raw_hid_interface(device, keeb_config))
+            .filter(|device| device.usb.is_none_or(|device_usb| device_usb == usb))
+            .map(|device| device.path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// Send the host OS to every connected device of the keyboard,
+    /// returns `false` when none is connected
+    pub fn probe_keeb(&mut self, keeb: &str) -> anyhow::Result<bool> {
+        let host = self.host.clone();
+        let default_layer = self
+            .config
+            .profile(&host.hostname)
+            .and_then(|profile| profile.default_layer);
+        self.for_each_device(keeb, command::HOST_OS, |keeb_config, session| {
+            handshake(keeb, keeb_config, &host, session)?;
+            if keeb_config.host_info {
+                write_message(session, keeb_config, &host.message())?;
+            }
+            if let Some(layer) = default_layer {
+                write_message(session, keeb_config, &Message::SetLayer { layer })?;
+            }
+            if keeb_config.time_sync.is_some() {
+                write_message(session, keeb_config, &time_sync::local_time())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Probe the keyboard again and switch it back to its last layer,
+    /// for boards that lost their state while the host slept
+    pub fn reprobe_keeb(&mut self, keeb: &str) -> anyhow::Result<bool> {
+        let layer = self.registry.layer(keeb);
+        if !self.probe_keeb(keeb)? {
+            return Ok(false);
+        }
+        if let Some(layer) = layer {
+            self.send(keeb, &Message::SetLayer { layer })?;
+        }
+        Ok(true)
+    }
+
+    /// Send a message to every connected device of the keyboard,
+    /// returns `false` when none is connected
+    pub fn send(&mut self, keeb: &str, message: &Message) -> anyhow::Result<bool> {
+        let sent = self.for_each_device(keeb, message.command(), |keeb_config, session| {
+            write_message(session, keeb_config, message)
+        })?;
+        if let (true, Message::SetLayer { layer }) = (sent, message) {
+            self.registry.layer_sent(keeb, *layer);
+        }
+        Ok(sent)
+    }
+
+    /// Send a raw packet to every connected device of the keyboard, bypassing the protocol framing,
+    /// returns `false` when none is connected
+    pub fn send_raw(&mut self, keeb: &str, packet: &[u8]) -> anyhow::Result<bool> {
+        let command = packet.first().copied().unwrap_or_default();
+        self.for_each_device(keeb, command, |keeb_config, session| {
+            write_report(session, keeb_config, packet)
+        })
+    }
+
+    /// Send a message to every probed keyboard that's still connected
+    pub fn broadcast(&mut self, message: &Message) {
+        self.broadcast_where(|_| true, message);
+    }
+
+    /// Send a message to the connected keyboards whose config opts in to it
+    pub fn broadcast_where(
+        &mut self,
+        opted_in: impl Fn(&KeyboardConfig) -> bool,
+        message: &Message,
+    ) {
+        let keebs: Vec<_> = self
+            .config
+            .keyboards
+            .iter()
+            .filter(|(keeb, keeb_config)| opted_in(keeb_config) && self.registry.is_connected(keeb))
+            .map(|(keeb, _)| keeb.clone())
+            .collect();
+        for keeb in keebs {
+            if let Err(err) = self.send(&keeb, message) {
+                tracing::warn!(keeb, ?message, "Failed to send: {err:#}");
+            }
+        }
+    }
+
+    /// Pass the session of every connected raw HID interface matching the keyboard config to `action`,
+    /// opening the interfaces without one
+    ///
+    /// A failing device doesn't stop the others, the error is only returned when all of them failed.
+    /// Returns `false` when no device is connected.
+    /// `command` is recorded as the last message of the keyboard when any device succeeded.
+    fn for_each_device(
+        &mut self,
+        keeb: &str,
+        command: u8,
+        mut action: impl FnMut(&KeyboardConfig, &Session<T::Device>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<bool> {
+        let keeb_config = self
+            .config
+            .keyboards
+            .get(keeb)
+            .context(format!("Unknown keeb '{keeb}'"))?;
+        let devices: Vec<_> = self
+            .transport
+            .devices()?
+            .into_iter()
+            .filter(|device| is_raw_hid_interface(device, keeb_config))
+            .collect();
+        let paths: Vec<_> = devices
+            .iter()
+            .map(|device| device.path.to_string_lossy().into_owned())
+            .collect();
+        // reopen the interfaces that failed or were replaced
+        self.sessions.retain(|path, session| {
+            session.keeb() != keeb || (!session.is_closed() && paths.contains(path))
+        });
+        let mut last_err = None;
+        let mut reached = false;
+        for (device, path) in devices.iter().zip(paths) {
+            let _span = tracing::debug_span!("device", keeb, path).entered();
+            let result = match self.sessions.entry(path.clone()) {
+                Entry::Occupied(entry) => Ok(&*entry.into_mut()),
+                Entry::Vacant(entry) => self.transport.open(device).map(|opened| {
+                    &*entry.insert(Session::open(
+                        keeb,
+                        path.clone(),
+                        opened,
+                        keeb_config,
+                        self.events.clone(),
+                    ))
+                }),
+            }
+            .and_then(|session| action(keeb_config, session));
+            match result {
+                Ok(()) => reached = true,
+                Err(err) => {
+                    tracing::warn!("Device failed: {err:#}");
+                    self.sessions.remove(&path);
+                    last_err = Some(err);
+                }
+            }
+        }
+        if reached {
+            self.registry.message_sent(keeb, command);
+        }
+        match last_err {
+            Some(err) if !reached => Err(err),
+            _ => Ok(!devices.is_empty()),
+        }
+    }
+}
+
+/// [`BoardConnection`] shared between the hotplug callback and the host watchers
+pub struct SharedConnection<T: HidTransport>(Arc<Mutex<BoardConnection<T>>>);
+impl<T: HidTransport> SharedConnection<T> {
+    pub fn new(connection: BoardConnection<T>) -> Self {
+        Self(Arc::new(Mutex::new(connection)))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, BoardConnection<T>> {
+        // a panicking watcher doesn't leave the connection in an inconsistent state
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+impl<T: HidTransport + 'static> SharedConnection<T> {
+    /// Send the host OS to every configured keyboard matching the plugged in USB device,
+    /// each in a background thread so the hotplug events keep flowing
+    pub fn probe(&self, vendor_id: u16, product_id: u16, usb: UsbLocation) {
+        for keeb in self.lock().keebs_matching(vendor_id, product_id) {
+            let connection = self.clone();
+            thread::spawn(move || connection.probe_with_retry(&keeb, usb));
+        }
+    }
+
+    /// Wait for the HID node of the keyboard to show up, then send the host OS,
+    /// retrying failures (e.g. udev still applying permissions) with exponential backoff
+    ///
+    /// The connection is only locked for each attempt, not while waiting.
+    /// A successfully probed keyboard is added to the registry under the USB device location.
+    pub fn probe_with_retry(&self, keeb: &str, usb: UsbLocation) {
+        let retry = self.lock().config().retry.clone();
+        // other configs with the same IDs may match the plugged in device instead
+        let Some(paths) = self.await_ready(keeb, usb, &retry) else {
+            tracing::debug!(keeb, "Not connected");
+            return;
+        };
+        let Some((mut connection, attempt)) =
+            self.retry_probe(keeb, &retry, |connection| connection.probe_keeb(keeb))
+        else {
+            return;
+        };
+        connection.registry.arrived(keeb, usb, paths);
+        connection.registry.message_sent(keeb, command::HOST_OS);
+        connection.events.publish(BoardEvent::Connected {
+            keeb: keeb.to_owned(),
+        });
+        tracing::info!(keeb, attempt, usb.bus, usb.address, "Connected");
+    }
+
+    /// Probe the connected keyboards again in background threads, see [`BoardConnection::reprobe_keeb`]
+    ///
+    /// Boards that stay powered through a suspend may reset without being re-enumerated,
+    /// so no hotplug event would probe them.
+    pub fn reprobe_connected(&self) {
+        let mut keebs: Vec<_> = {
+            let connection = self.lock();
+            connection
+                .registry()
+                .boards()
+                .iter()
+                .map(|board| board.keeb.clone())
+                .collect()
+        };
+        keebs.sort();
+        keebs.dedup();
+        for keeb in keebs {
+            let connection = self.clone();
+            thread::spawn(move || {
+                let retry = connection.lock().config().retry.clone();
+                if let Some((connection, attempt)) =
+                    connection
+                        .retry_probe(&keeb, &retry, |connection| connection.reprobe_keeb(&keeb))
+                {
+                    connection
+                        .events
+                        .publish(BoardEvent::Reprobed { keeb: keeb.clone() });
+                    tracing::info!(keeb, attempt, "Probed again");
+                }
+            });
+        }
+    }
+
+    /// Call `probe` until it succeeds, retrying failures with exponential backoff
+    ///
+    /// Returns the connection, still locked after the successful attempt, and the attempt number.
+    fn retry_probe(
+        &self,
+        keeb: &str,
+        retry: &RetryConfig,
+        probe: impl Fn(&mut BoardConnection<T>) -> anyhow::Result<bool>,
+    ) -> Option<(MutexGuard<'_, BoardConnection<T>>, u32)> {
+        let mut backoff = Duration::from_millis(retry.backoff_ms);
+        for attempt in 1..=retry.attempts {
+            let mut connection = self.lock();
+            match probe(&mut connection) {
+                Ok(true) => return Some((connection, attempt)),
+                Ok(false) => {
+                    tracing::debug!(keeb, "Disconnected before the probe");
+                    return None;
+                }
+                Err(err) if attempt < retry.attempts => {
+                    tracing::warn!(keeb, attempt, ?backoff, "Probe failed, retrying: {err:#}");
+                }
+                Err(err) => {
+                    tracing::error!(keeb, attempts = retry.attempts, "Probe failed: {err:#}");
+                    return None;
+                }
+            }
+            drop(connection);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_millis(retry.max_backoff_ms));
+        }
+        None
+    }
+
+    /// Poll the HID devices until the keyboard's raw HID interface on the USB device is enumerated,
+    /// returns its paths or `None` when it didn't show up within the configured timeout
+    fn await_ready(
+        &self,
+        keeb: &str,
+        usb: UsbLocation,
+        retry: &RetryConfig,
+    ) -> Option<Vec<String>> {
+        let deadline = Instant::now() + Duration::from_millis(retry.ready_timeout_ms);
+        loop {
+            let paths = self.lock().device_paths(keeb, usb);
+            match paths {
+                Ok(paths) if !paths.is_empty() => return Some(paths),
+                Ok(_) => {}
+                Err(err) => tracing::warn!(keeb, "Failed to list HID devices: {err:#}"),
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(READY_POLL_INTERVAL);
+        }
+    }
+}
+impl<T: HidTransport> Clone for SharedConnection<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+impl<T: HidTransport + 'static, C: rusb::UsbContext> rusb::Hotplug<C> for SharedConnection<T> {
+    fn device_arrived(&mut self, device: rusb::Device<C>) {
+        if let Ok(desc) = device.device_descriptor() {
+            tracing::debug!(
+                vendor_id = format_args!("{:#06x}", desc.vendor_id()),
+                product_id = format_args!("{:#06x}", desc.product_id()),
+                bus = device.bus_number(),
+                address = device.address(),
+                "USB device arrived"
+            );
+            self.probe(desc.vendor_id(), desc.product_id(), usb_location(&device));
+        }
+    }
+
+    fn device_left(&mut self, device: rusb::Device<C>) {
+        tracing::debug!(
+            bus = device.bus_number(),
+            address = device.address(),
+            "USB device left"
+        );
+        self.lock().device_left(usb_location(&device));
+    }
+}
+
+fn usb_location<C: rusb::UsbContext>(device: &rusb::Device<C>) -> UsbLocation {
+    UsbLocation {
+        bus: device.bus_number(),
+        address: device.address(),
+    }
+}
+
+/// Detect the host and apply its profile to the config
+fn resolve_host(config: &mut Config) -> HostInfo {
+    let mut host = HostInfo::detect(&config.host);
+    host.profile_id = config.apply_profile(&host.hostname);
+    host
+}
+
+/// Whether the device is the raw HID interface of the configured keyboard
+fn is_raw_hid_interface(device: &DeviceInfo, keeb_config: &KeyboardConfig) -> bool {
+    device.vendor_id == keeb_config.vendor_id
+        && device.product_id == keeb_config.product_id
+        && device.usage == keeb_config.usage
+        && device.usage_page == keeb_config.usage_page
+        && keeb_config
+            .interface_number
+            .is_none_or(|interface_number| device.interface_number == interface_number)
+        && glob_matches(&keeb_config.serial_number, &device.serial_number)
+        && glob_matches(&keeb_config.manufacturer, &device.manufacturer)
+        && glob_matches(&keeb_config.product, &device.product)
+}
+
+/// A missing pattern matches anything, a missing device string only matches a missing pattern
+fn glob_matches(pattern: &Option<String>, value: &Option<String>) -> bool {
+    let Some(pattern) = pattern else {
+        return true;
+    };
+    // patterns are validated with the config
+    Pattern::new(pattern)
+        .is_ok_and(|pattern| value.as_ref().is_some_and(|value| pattern.matches(value)))
+}
+
+/// Send the host OS in the configured format, waiting for the ack when the keyboard is configured for it,
+/// or the custom payload as a single report
+///
+/// A missing ack is only reported, the board is kept in use.
+fn handshake(
+    keeb: &str,
+    keeb_config: &KeyboardConfig,
+    host: &HostInfo,
+    session: &Session<impl HidHandle>,
+) -> anyhow::Result<()> {
+    if let Some(payload) = &keeb_config.payload {
+        return write_report(session, keeb_config, &payload.render(host));
+    }
+    let os_code = host.os.code();
+    if keeb_config.protocol == Protocol::Legacy {
+        return write_report(session, keeb_config, &[command::HOST_OS, os_code]);
+    }
+    let message = Message::HostOs {
+        os_code,
+        profile_id: host.profile_id,
+    };
+    let Some(handshake) = &keeb_config.handshake else {
+        return write_message(session, keeb_config, &message);
+    };
+    for attempt in 1..=handshake.retries + 1 {
+        write_message(session, keeb_config, &message)?;
+        match await_ack(session, handshake, os_code) {
+            Ack::Received => return Ok(()),
+            Ack::Mismatch(acked) => {
+                tracing::warn!(
+                    keeb,
+                    acked,
+                    os_code,
+                    "Keeb acknowledged a different OS code"
+                );
+                return Ok(());
+            }
+            Ack::Missing => tracing::warn!(
+                keeb,
+                attempt,
+                attempts = handshake.retries + 1,
+                timeout_ms = handshake.timeout_ms,
+                "Keeb did not acknowledge the host report"
+            ),
+        }
+    }
+    tracing::error!(
+        keeb,
+        protocol_version = protocol::PROTOCOL_VERSION,
+        "Keeb ignored the host report, check that the firmware speaks the protocol version"
+    );
+    Ok(())
+}
+
+enum Ack {
+    Received,
+    /// The board replied, but with a different OS code
+    Mismatch(u8),
+    Missing,
+}
+
+/// Write all packets of a message
+fn write_message(
+    session: &Session<impl HidHandle>,
+    keeb_config: &KeyboardConfig,
+    message: &Message,
+) -> anyhow::Result<()> {
+    for packet in protocol::encode(message)? {
+        write_report(session, keeb_config, &packet)?;
+    }
+    Ok(())
+}
+
+/// Write the data as a single report, prefixed by the mandatory report ID and zero padded to the report size
+fn write_report(
+    session: &Session<impl HidHandle>,
+    keeb_config: &KeyboardConfig,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    if data.len() > keeb_config.report_size {
+        anyhow::bail!(
+            "{} bytes exceed the {} byte report size",
+            data.len(),
+            keeb_config.report_size
+        );
+    }
+    let mut report = vec![0; keeb_config.report_size + 1];
+    report[0] = keeb_config.report_id;
+    report[1..=data.len()].copy_from_slice(data);
+    session.write(&report)?;
+    tracing::trace!(len = report.len(), ?report, "Wrote report");
+    Ok(())
+}
+
+/// Wait for the host OS ack from the session's reader until the handshake timeout elapses,
+/// other board messages arriving in the meantime are skipped
+fn await_ack(session: &Session<impl HidHandle>, handshake: &HandshakeConfig, os_code: u8) -> Ack {
+    let deadline = Instant::now() + Duration::from_millis(handshake.timeout_ms);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match session.recv_ack(remaining) {
+            None => return Ack::Missing,
+            Some(Message::Ack {
+                command: command::HOST_OS,
+                value: acked,
+            }) => {
+                return if acked == os_code {
+                    Ack::Received
+                } else {
+                    Ack::Mismatch(acked)
+                };
+            }
+            Some(message) => tracing::debug!(?message, "Skipped while awaiting the ack"),
+        }
+    }
+}
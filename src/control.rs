@@ -0,0 +1,253 @@
+//! Control socket for other programs to reach the keyboards through the running daemon
+//!
+//! The protocol is JSON lines, one request per line answered by one response line:
+//!
+//! | request                                                   | response                     |
+//! |-----------------------------------------------------------|------------------------------|
+//! | `{"cmd":"list"}`                                          | `{"ok":true,"boards":[...]}` |
+//! | `{"cmd":"send","keeb":"klor","bytes":[42,1]}`             | `{"ok":true,"sent":true}`    |
+//! | `{"cmd":"set_layer","layer":2}`, `keeb` is optional       | `{"ok":true}`                |
+//! | `{"cmd":"subscribe"}`                                     | `{"ok":true}`, then events   |
+//!
+//! Failures answer `{"ok":false,"error":"..."}`. After `subscribe` the connection only carries
+//! [`BoardEvent`](crate::events::BoardEvent)s, e.g. `{"event":"connected","keeb":"klor"}`.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{connection::SharedConnection, protocol::Message, transport::HidTransport};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    List,
+    /// Raw packet, bypassing the protocol framing like the `send` subcommand
+    Send {
+        keeb: String,
+        bytes: Vec<u8>,
+    },
+    /// Switch the layer of one keyboard or of all connected ones
+    SetLayer {
+        keeb: Option<String>,
+        layer: u8,
+    },
+    Subscribe,
+}
+
+/// `keeb_os_probe/control.sock` in the runtime dir, or in the temp dir without one
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("keeb_os_probe")
+        .join("control.sock")
+}
+
+/// Listen on the control socket, serving each client in its own thread
+///
+/// Fails when another daemon already listens on the socket.
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) -> anyhow::Result<()> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        anyhow::bail!("Another daemon is listening on {path:?}");
+    }
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    // a stale socket of a daemon that didn't shut down cleanly
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).context(format!("Control socket: {path:?}"))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    tracing::debug!(?path, "Control socket listening");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("Control socket accept failed: {err}");
+                    continue;
+                }
+            };
+            let connection = connection.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve(&connection, stream) {
+                    tracing::debug!("Control client left: {err:#}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Create the socket dir accessible to this user only
+///
+/// The temp dir fallback is shared, so an existing dir has to be a real directory owned by this user,
+/// not one another user created to intercept the socket.
+fn create_private_dir(dir: &Path) -> anyhow::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err).context(format!("Control socket dir: {dir:?}")),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    // SAFETY: geteuid has no preconditions and can't fail
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid {
+        anyhow::bail!("Control socket dir {dir:?} isn't a directory owned by this user");
+    }
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+fn serve<T: HidTransport + 'static>(
+    connection: &SharedConnection<T>,
+    stream: UnixStream,
+) -> anyhow::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                write_line(&mut writer, &error(format!("Invalid request: {err}")))?;
+                continue;
+            }
+        };
+        tracing::debug!(?request, "Control request");
+        if let Request::Subscribe = request {
+            // subscribe before answering so no event slips through
+            let events = connection.lock().events().subscribe();
+            write_line(&mut writer, &json!({ "ok": true }))?;
+            for event in events {
+                write_line(&mut writer, &serde_json::to_value(event)?)?;
+            }
+            return Ok(());
+        }
+        let response = handle(connection, request).unwrap_or_else(|err| error(format!("{err:#}")));
+        write_line(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+fn handle<T: HidTransport + 'static>(
+    connection: &SharedConnection<T>,
+    request: Request,
+) -> anyhow::Result<Value> {
+    let mut connection = connection.lock();
+    Ok(match request {
+        Request::List => json!({ "ok": true, "boards": connection.registry().boards() }),
+        Request::Send { keeb, bytes } => {
+            json!({ "ok": true, "sent": connection.send_raw(&keeb, &bytes)? })
+        }
+        Request::SetLayer {
+            keeb: Some(keeb),
+            layer,
+        } => {
+            json!({ "ok": true, "sent": connection.send(&keeb, &Message::SetLayer { layer })? })
+        }
+        Request::SetLayer { keeb: None, layer } => {
+            connection.broadcast(&Message::SetLayer { layer });
+            json!({ "ok": true })
+        }
+        Request::Subscribe => unreachable!("Subscriptions are served by the caller"),
+    })
+}
+
+fn error(message: String) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+fn write_line(writer: &mut impl Write, value: &Value) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+/// Send a request to the running daemon, calling `on_line` with each response line
+///
+/// Returns after the response, or when the daemon closes a subscription.
+pub fn request(request: &Value, on_line: &mut dyn FnMut(Value)) -> anyhow::Result<()> {
+    let path = socket_path();
+    let mut stream =
+        UnixStream::connect(&path).context(format!("Is the daemon running? {path:?}"))?;
+    write_line(&mut stream, request)?;
+    let subscribe = request.get("cmd").and_then(Value::as_str) == Some("subscribe");
+    for line in BufReader::new(stream).lines() {
+        on_line(serde_json::from_str(&line?)?);
+        if !subscribe {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transport::{MockTransport, MOCK_KEYBOARD},
+        BoardConnection, Config,
+    };
+
+    #[test]
+    fn socket_dir_is_private() {
+        let root = std::env::temp_dir().join(format!("keeb_os_probe_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        let mode = |dir: &Path| fs::metadata(dir).unwrap().mode() & 0o777;
+
+        let created = root.join("created");
+        create_private_dir(&created).unwrap();
+        assert_eq!(mode(&created), 0o700);
+
+        let open = root.join("open");
+        fs::create_dir(&open).unwrap();
+        fs::set_permissions(&open, fs::Permissions::from_mode(0o777)).unwrap();
+        create_private_dir(&open).unwrap();
+        assert_eq!(mode(&open), 0o700);
+
+        let link = root.join("link");
+        std::os::unix::fs::symlink(&created, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn answers_requests_line_by_line() {
+        let config: Config = toml::from_str(MOCK_KEYBOARD).unwrap();
+        let connection = SharedConnection::new(BoardConnection::with_transport(
+            MockTransport::new(),
+            config,
+        ));
+        let (mut client, server) = UnixStream::pair().unwrap();
+        thread::spawn(move || serve(&connection, server));
+        client
+            .write_all(b"{\"cmd\":\"list\"}\n\n{\"cmd\":\"reboot\"}\n")
+            .unwrap();
+        let mut lines = BufReader::new(client).lines();
+        let mut response =
+            || serde_json::from_str::<Value>(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(response(), json!({ "ok": true, "boards": [] }));
+        let invalid = response();
+        assert_eq!(invalid["ok"], false);
+        assert!(invalid["error"].as_str().unwrap().contains("reboot"));
+    }
+}
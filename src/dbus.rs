@@ -0,0 +1,222 @@
+//! Session bus service for desktop scripts and shell extensions
+//!
+//! Owns [`BUS_NAME`] and serves the `io.github.keeb_os_probe.Keyboards` interface at
+//! [`OBJECT_PATH`], the same path the [`DbusSignal`](crate::ActionConfig::DbusSignal) actions
+//! are emitted on. E.g. `busctl --user call io.github.keeb_os_probe /io/github/keeb_os_probe
+//! io.github.keeb_os_probe.Keyboards SetLayer sy klor 2`.
+
+use std::thread;
+
+use zbus::{
+    blocking::{object_server::InterfaceRef, Connection},
+    fdo,
+    object_server::SignalEmitter,
+};
+
+use crate::{
+    connection::SharedConnection, events::BoardEvent, protocol::Message, transport::HidTransport,
+};
+
+pub const BUS_NAME: &str = "io.github.keeb_os_probe";
+pub const OBJECT_PATH: &str = crate::actions::SIGNAL_PATH;
+
+struct Keyboards<T: HidTransport> {
+    connection: SharedConnection<T>,
+}
+
+#[zbus::interface(name = "io.github.keeb_os_probe.Keyboards")]
+impl<T: HidTransport + 'static> Keyboards<T> {
+    /// The connected keyboards as (name, USB bus, USB address, HID paths, arrival unix time)
+    fn list_keyboards(&self) -> Vec<(String, u8, u8, Vec<String>, u64)> {
+        self.connection
+            .lock()
+            .registry()
+            .boards()
+            .iter()
+            .map(|board| {
+                (
+                    board.keeb.clone(),
+                    board.usb.bus,
+                    board.usb.address,
+                    board.paths.clone(),
+                    board.arrived_at,
+                )
+            })
+            .collect()
+    }
+
+    /// Send a raw packet, returns whether the keyboard is connected
+    fn send(&self, keeb: &str, bytes: Vec<u8>) -> fdo::Result<bool> {
+        self.connection
+            .lock()
+            .send_raw(keeb, &bytes)
+            .map_err(|err| fdo::Error::Failed(format!("{err:#}")))
+    }
+
+    /// Switch the layer of a keyboard, an empty name switches all the connected ones
+    fn set_layer(&self, keeb: &str, layer: u8) -> fdo::Result<bool> {
+        let mut connection = self.connection.lock();
+        let message = Message::SetLayer { layer };
+        if keeb.is_empty() {
+            connection.broadcast(&message);
+            return Ok(true);
+        }
+        connection
+            .send(keeb, &message)
+            .map_err(|err| fdo::Error::Failed(format!("{err:#}")))
+    }
+
+    #[zbus(signal)]
+    async fn keyboard_connected(emitter: &SignalEmitter<'_>, keeb: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn keyboard_disconnected(emitter: &SignalEmitter<'_>, keeb: &str) -> zbus::Result<()>;
+
+    /// A keyboard originated message, serialised as JSON like on the control socket
+    #[zbus(signal)]
+    async fn keyboard_message(
+        emitter: &SignalEmitter<'_>,
+        keeb: &str,
+        message: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Serve the keyboards on `bus` and block the current thread, turning the board events into signals
+pub fn serve<T: HidTransport + 'static>(
+    bus: &Connection,
+    connection: SharedConnection<T>,
+) -> anyhow::Result<()> {
+    let events = connection.lock().events().subscribe();
+    bus.object_server()
+        .at(OBJECT_PATH, Keyboards { connection })?;
+    bus.request_name(BUS_NAME)?;
+    let keyboards: InterfaceRef<Keyboards<T>> = bus.object_server().interface(OBJECT_PATH)?;
+    let emitter = keyboards.signal_emitter();
+    for event in events {
+        let result = zbus::block_on(async {
+            match &event {
+                BoardEvent::Connected { keeb } => {
+                    Keyboards::<T>::keyboard_connected(emitter, keeb).await
+                }
+                BoardEvent::Disconnected { keeb } => {
+                    Keyboards::<T>::keyboard_disconnected(emitter, keeb).await
+                }
+                // the keyboard stays connected
+                BoardEvent::Reprobed { .. } => Ok(()),
+                BoardEvent::Message { keeb, message } => {
+                    let message = serde_json::to_string(message)
+                        .map_err(|err| zbus::Error::Failure(err.to_string()))?;
+                    Keyboards::<T>::keyboard_message(emitter, keeb, &message).await
+                }
+            }
+        });
+        if let Err(err) = result {
+            tracing::warn!(?event, "Failed to emit the D-Bus signal: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Own [`BUS_NAME`] on the session bus in a background thread
+///
+/// Returns the service's connection, for signals that have to come from the name owner,
+/// or `None` without a session bus.
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) -> Option<Connection> {
+    let bus = match Connection::session() {
+        Ok(bus) => bus,
+        Err(err) => {
+            tracing::error!("D-Bus service stopped: {err:#}");
+            return None;
+        }
+    };
+    {
+        let bus = bus.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(&bus, connection) {
+                tracing::error!("D-Bus service stopped: {err:#}");
+            }
+        });
+    }
+    Some(bus)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use zbus::{blocking::MessageIterator, message::Type, MatchRule};
+
+    use super::*;
+    use crate::{
+        test_bus::PrivateBus,
+        transport::{MockTransport, MOCK_KEYBOARD},
+        BoardConnection, Config,
+    };
+
+    const INTERFACE: &str = "io.github.keeb_os_probe.Keyboards";
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn serves_keyboards_on_a_private_bus() {
+        let bus = PrivateBus::start();
+        let config: Config = toml::from_str(MOCK_KEYBOARD).unwrap();
+        let connection = SharedConnection::new(BoardConnection::with_transport(
+            MockTransport::new(),
+            config,
+        ));
+        {
+            let service_bus = bus.connect();
+            let connection = connection.clone();
+            thread::spawn(move || serve(&service_bus, connection));
+        }
+        let client = bus.connect();
+        let call = |method: &str| {
+            client.call_method(Some(BUS_NAME), OBJECT_PATH, Some(INTERFACE), method, &())
+        };
+        let mut reply = call("ListKeyboards");
+        for _ in 0..100 {
+            if reply.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            reply = call("ListKeyboards");
+        }
+        let keyboards: Vec<(String, u8, u8, Vec<String>, u64)> =
+            reply.unwrap().body().deserialize().unwrap();
+        assert!(keyboards.is_empty());
+        let send: bool = client
+            .call_method(
+                Some(BUS_NAME),
+                OBJECT_PATH,
+                Some(INTERFACE),
+                "Send",
+                &("klor", vec![42u8, 1]),
+            )
+            .unwrap()
+            .body()
+            .deserialize()
+            .unwrap();
+        assert!(!send);
+
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(INTERFACE)
+            .unwrap()
+            .build();
+        let mut signals = MessageIterator::for_match_rule(rule, &client, None).unwrap();
+        connection.lock().events().publish(BoardEvent::Message {
+            keeb: "klor".to_owned(),
+            message: Message::Action {
+                name: "lock".to_owned(),
+            },
+        });
+        let signal = signals.next().unwrap().unwrap();
+        assert_eq!(
+            signal.header().member().unwrap().as_str(),
+            "KeyboardMessage"
+        );
+        let (keeb, message): (String, String) = signal.body().deserialize().unwrap();
+        assert_eq!(keeb, "klor");
+        assert_eq!(message, r#"{"type":"action","name":"lock"}"#);
+    }
+}
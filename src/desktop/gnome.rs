@@ -0,0 +1,82 @@
+//! GNOME input sources, read through `gsettings` as GNOME Shell keeps the active one to itself
+
+use std::{
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+
+use super::KeyboardLayout;
+
+const SCHEMA: &str = "org.gnome.desktop.input-sources";
+
+pub fn watch_layout(on_change: &mut dyn FnMut(KeyboardLayout)) -> anyhow::Result<()> {
+    let mut monitor = Command::new("gsettings")
+        .args(["monitor", SCHEMA])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to start gsettings")?;
+    on_change(current_layout()?);
+    let lines = BufReader::new(monitor.stdout.take().context("No gsettings output")?).lines();
+    for line in lines {
+        // switching moves the source to the front of the most recently used ones
+        if line?.starts_with("mru-sources:") {
+            on_change(current_layout()?);
+        }
+    }
+    anyhow::bail!("gsettings monitor exited with {}", monitor.wait()?)
+}
+
+fn current_layout() -> anyhow::Result<KeyboardLayout> {
+    let sources = source_ids(&get("sources")?);
+    let Some(current) = source_ids(&get("mru-sources")?)
+        .into_iter()
+        .next()
+        .or_else(|| sources.first().cloned())
+    else {
+        return Ok(KeyboardLayout::default());
+    };
+    Ok(KeyboardLayout {
+        index: sources
+            .iter()
+            .position(|source| *source == current)
+            .and_then(|index| index.try_into().ok())
+            .unwrap_or_default(),
+        name: current,
+    })
+}
+
+fn get(key: &str) -> anyhow::Result<String> {
+    let output = Command::new("gsettings")
+        .args(["get", SCHEMA, key])
+        .output()
+        .context("Failed to run gsettings")?;
+    if !output.status.success() {
+        anyhow::bail!("gsettings get {key} exited with {}", output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Layout IDs of a GVariant `a(ss)` list of (type, ID) pairs, e.g. `[('xkb', 'us'), ('ibus', 'anthy')]`
+fn source_ids(sources: &str) -> Vec<String> {
+    let strings: Vec<_> = sources.split('\'').skip(1).step_by(2).collect();
+    strings
+        .chunks_exact(2)
+        .map(|source| source[1].to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_input_sources() {
+        assert_eq!(
+            source_ids("[('xkb', 'us'), ('xkb', 'cz+qwerty')]\n"),
+            ["us", "cz+qwerty"]
+        );
+        assert!(source_ids("@a(ss) []\n").is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+//! [Hyprland IPC](https://wiki.hyprland.org/IPC/) client
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{FocusedWindow, KeyboardLayout};
+
+/// Send a command to the request socket and return the raw reply
+pub fn request(command: &str) -> anyhow::Result<String> {
+    let mut stream = connect(".socket.sock")?;
+    stream.write_all(command.as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// Connect to the event socket, yielding `(event, data)` pairs
+pub fn events() -> anyhow::Result<impl Iterator<Item = anyhow::Result<(String, String)>>> {
+    let stream = connect(".socket2.sock")?;
+    Ok(BufReader::new(stream).lines().map(|line| {
+        let line = line?;
+        let (event, data) = line
+            .split_once(">>")
+            .context(format!("Malformed Hyprland event: {line}"))?;
+        Ok((event.to_owned(), data.to_owned()))
+    }))
+}
+
+pub fn watch_focus(on_focus: &mut dyn FnMut(FocusedWindow)) -> anyhow::Result<()> {
+    let active: Value = serde_json::from_str(&request("j/activewindow")?)?;
+    on_focus(FocusedWindow {
+        class: active["class"].as_str().unwrap_or_default().to_owned(),
+        title: active["title"].as_str().unwrap_or_default().to_owned(),
+    });
+    for event in events()? {
+        let (event, data) = event?;
+        if event == "activewindow" {
+            // the class can't contain a comma, the title can
+            let (class, title) = data.split_once(',').unwrap_or((&data, ""));
+            on_focus(FocusedWindow {
+                class: class.to_owned(),
+                title: title.to_owned(),
+            });
+        }
+    }
+    anyhow::bail!("Hyprland event socket closed")
+}
+
+pub fn watch_layout(on_change: &mut dyn FnMut(KeyboardLayout)) -> anyhow::Result<()> {
+    let devices = || -> anyhow::Result<Value> { Ok(serde_json::from_str(&request("j/devices")?)?) };
+    if let Some(layout) = keyboard_layout(&devices()?, None) {
+        on_change(layout);
+    }
+    for event in events()? {
+        let (event, data) = event?;
+        if event == "activelayout" {
+            // the event only names the layout, the index comes from the device list
+            let (keyboard, _) = data.split_once(',').unwrap_or((&data, ""));
+            if let Some(layout) = keyboard_layout(&devices()?, Some(keyboard)) {
+                on_change(layout);
+            }
+        }
+    }
+    anyhow::bail!("Hyprland event socket closed")
+}
+
+/// Layout of the named keyboard, or of the main one
+fn keyboard_layout(devices: &Value, keyboard: Option<&str>) -> Option<KeyboardLayout> {
+    let keyboards = devices["keyboards"].as_array()?;
+    let keyboard = keyboards
+        .iter()
+        .find(|device| match keyboard {
+            Some(name) => device["name"] == name,
+            None => device["main"] == true,
+        })
+        .or_else(|| keyboards.first())?;
+    Some(KeyboardLayout {
+        // older Hyprland versions don't report the index
+        index: keyboard["active_layout_index"]
+            .as_u64()
+            .and_then(|index| index.try_into().ok())
+            .unwrap_or_default(),
+        name: keyboard["active_keymap"].as_str()?.to_owned(),
+    })
+}
+
+fn connect(socket: &str) -> anyhow::Result<UnixStream> {
+    let signature =
+        env::var("HYPRLAND_INSTANCE_SIGNATURE").context("HYPRLAND_INSTANCE_SIGNATURE not set")?;
+    // sockets moved from /tmp to the runtime dir in Hyprland 0.40
+    let candidates = dirs::runtime_dir()
+        .map(|dir| dir.join("hypr"))
+        .into_iter()
+        .chain([PathBuf::from("/tmp/hypr")])
+        .map(|dir| dir.join(&signature).join(socket));
+    for path in candidates {
+        if path.exists() {
+            return UnixStream::connect(&path).context(format!("Hyprland socket: {path:?}"));
+        }
+    }
+    anyhow::bail!("Hyprland socket {socket} not found")
+}
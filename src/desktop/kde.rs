@@ -0,0 +1,48 @@
+//! KDE Plasma keyboard layouts, served by the KWin `org.kde.keyboard` D-Bus service
+
+use zbus::blocking::Connection;
+
+use super::KeyboardLayout;
+
+#[zbus::proxy(
+    interface = "org.kde.KeyboardLayouts",
+    default_service = "org.kde.keyboard",
+    default_path = "/Layouts",
+    gen_async = false
+)]
+trait KeyboardLayouts {
+    #[zbus(name = "getLayout")]
+    fn get_layout(&self) -> zbus::Result<u32>;
+
+    /// (short name, variant, long name) of the configured layouts
+    #[zbus(name = "getLayoutsList")]
+    fn get_layouts_list(&self) -> zbus::Result<Vec<(String, String, String)>>;
+
+    #[zbus(signal, name = "layoutChanged")]
+    fn layout_changed(&self, index: u32) -> zbus::Result<()>;
+}
+
+pub fn watch_layout(on_change: &mut dyn FnMut(KeyboardLayout)) -> anyhow::Result<()> {
+    let bus = Connection::session()?;
+    let layouts = KeyboardLayoutsProxy::new(&bus)?;
+    let changes = layouts.receive_layout_changed()?;
+    on_change(keyboard_layout(&layouts, layouts.get_layout()?)?);
+    for change in changes {
+        on_change(keyboard_layout(&layouts, change.args()?.index)?);
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+fn keyboard_layout(layouts: &KeyboardLayoutsProxy, index: u32) -> anyhow::Result<KeyboardLayout> {
+    // the list is queried each time as it changes with the settings
+    let name = layouts
+        .get_layouts_list()?
+        .into_iter()
+        .nth(index as usize)
+        .map(|(_, _, long_name)| long_name)
+        .unwrap_or_default();
+    Ok(KeyboardLayout {
+        index: index.try_into().unwrap_or(u8::MAX),
+        name,
+    })
+}
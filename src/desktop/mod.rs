@@ -0,0 +1,97 @@
+//! Clients for the window systems and desktops the host watchers listen to
+
+pub mod gnome;
+pub mod hyprland;
+pub mod kde;
+pub mod sway;
+pub mod x11;
+
+/// Window system of the graphical session the daemon runs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowSystem {
+    Sway,
+    Hyprland,
+    X11,
+}
+impl WindowSystem {
+    /// Detect the window system from the session environment,
+    /// compositors are preferred over Xwayland
+    pub fn detect() -> Option<Self> {
+        if std::env::var_os("SWAYSOCK").is_some() {
+            Some(Self::Sway)
+        } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            Some(Self::Hyprland)
+        } else if std::env::var_os("DISPLAY").is_some() {
+            Some(Self::X11)
+        } else {
+            None
+        }
+    }
+}
+
+/// Currently focused application window
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FocusedWindow {
+    /// X11 `WM_CLASS` class or Wayland app ID
+    pub class: String,
+    pub title: String,
+}
+
+/// Block the current thread, calling `on_focus` with the initially focused window
+/// and then whenever the focus or the focused window title changes
+pub fn watch_focus(
+    window_system: WindowSystem,
+    on_focus: &mut dyn FnMut(FocusedWindow),
+) -> anyhow::Result<()> {
+    match window_system {
+        WindowSystem::Sway => sway::watch_focus(on_focus),
+        WindowSystem::Hyprland => hyprland::watch_focus(on_focus),
+        WindowSystem::X11 => x11::watch_focus(on_focus),
+    }
+}
+
+/// Where the active keyboard layout is read from
+///
+/// GNOME and KDE keep their own layout switching, so they're preferred over the window system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutSource {
+    Gnome,
+    Kde,
+    Window(WindowSystem),
+}
+impl LayoutSource {
+    pub fn detect() -> Option<Self> {
+        let desktops = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+        if desktops.split(':').any(|desktop| desktop == "GNOME") {
+            Some(Self::Gnome)
+        } else if desktops.split(':').any(|desktop| desktop == "KDE") {
+            Some(Self::Kde)
+        } else {
+            WindowSystem::detect().map(Self::Window)
+        }
+    }
+}
+
+/// Active keyboard layout, i.e. the XKB group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyboardLayout {
+    /// Position in the configured layouts, starting at 0
+    pub index: u8,
+    /// Name as reported by the desktop, e.g. `English (US)`, or `us` on GNOME
+    pub name: String,
+}
+
+/// Block the current thread, calling `on_change` with the initial layout
+/// and then whenever the layout is switched
+pub fn watch_layout(
+    source: LayoutSource,
+    on_change: &mut dyn FnMut(KeyboardLayout),
+) -> anyhow::Result<()> {
+    match source {
+        LayoutSource::Gnome => gnome::watch_layout(on_change),
+        LayoutSource::Kde => kde::watch_layout(on_change),
+        LayoutSource::Window(WindowSystem::Sway) => sway::watch_layout(on_change),
+        LayoutSource::Window(WindowSystem::Hyprland) => hyprland::watch_layout(on_change),
+        LayoutSource::Window(WindowSystem::X11) => x11::watch_layout(on_change),
+    }
+}
@@ -0,0 +1,149 @@
+//! [sway IPC](https://man.archlinux.org/man/sway-ipc.7.en) client
+
+use std::{
+    env,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{FocusedWindow, KeyboardLayout};
+
+const MAGIC: &[u8] = b"i3-ipc";
+const GET_TREE: u32 = 4;
+const SUBSCRIBE: u32 = 2;
+const GET_INPUTS: u32 = 100;
+const WINDOW_EVENT: u32 = 0x8000_0003;
+const INPUT_EVENT: u32 = 0x8000_0015;
+
+pub struct SwayIpc {
+    stream: UnixStream,
+}
+impl SwayIpc {
+    /// Connect to the socket of the running sway session
+    pub fn connect() -> anyhow::Result<Self> {
+        let path = env::var_os("SWAYSOCK").context("SWAYSOCK not set")?;
+        Ok(Self {
+            stream: UnixStream::connect(&path).context(format!("Sway socket: {path:?}"))?,
+        })
+    }
+
+    /// Send a message and wait for its reply
+    pub fn request(&mut self, message_type: u32, payload: &str) -> anyhow::Result<Value> {
+        self.send(message_type, payload)?;
+        loop {
+            let (reply_type, reply) = self.receive()?;
+            if reply_type == message_type {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Subscribe to the given event types, after which [`SwayIpc::receive`] yields the events
+    pub fn subscribe(&mut self, events: &[&str]) -> anyhow::Result<()> {
+        let reply = self.request(SUBSCRIBE, &serde_json::to_string(events)?)?;
+        if reply["success"] != true {
+            anyhow::bail!("Sway refused subscription to {events:?}");
+        }
+        Ok(())
+    }
+
+    /// Read the next message, returning its type and JSON payload
+    pub fn receive(&mut self) -> anyhow::Result<(u32, Value)> {
+        let mut header = [0; MAGIC.len() + 8];
+        self.stream.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            anyhow::bail!("Invalid sway IPC magic");
+        }
+        let len = u32::from_ne_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into()?);
+        let message_type = u32::from_ne_bytes(header[MAGIC.len() + 4..].try_into()?);
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        Ok((message_type, serde_json::from_slice(&payload)?))
+    }
+
+    fn send(&mut self, message_type: u32, payload: &str) -> anyhow::Result<()> {
+        let mut message = MAGIC.to_vec();
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(payload.as_bytes());
+        Ok(self.stream.write_all(&message)?)
+    }
+}
+
+pub fn watch_focus(on_focus: &mut dyn FnMut(FocusedWindow)) -> anyhow::Result<()> {
+    let mut ipc = SwayIpc::connect()?;
+    if let Some(window) = find_focused(&ipc.request(GET_TREE, "")?) {
+        on_focus(window);
+    }
+    ipc.subscribe(&["window"])?;
+    loop {
+        let (event_type, event) = ipc.receive()?;
+        if event_type != WINDOW_EVENT {
+            continue;
+        }
+        let container = &event["container"];
+        let change = event["change"].as_str();
+        if change == Some("focus") || (change == Some("title") && container["focused"] == true) {
+            on_focus(focused_window(container));
+        }
+    }
+}
+
+pub fn watch_layout(on_change: &mut dyn FnMut(KeyboardLayout)) -> anyhow::Result<()> {
+    let mut ipc = SwayIpc::connect()?;
+    let inputs = ipc.request(GET_INPUTS, "")?;
+    if let Some(layout) = inputs
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(keyboard_layout)
+    {
+        on_change(layout);
+    }
+    ipc.subscribe(&["input"])?;
+    loop {
+        let (event_type, event) = ipc.receive()?;
+        // sent for every keyboard, they usually share the layout
+        if event_type != INPUT_EVENT || event["change"] != "xkb_layout" {
+            continue;
+        }
+        if let Some(layout) = keyboard_layout(&event["input"]) {
+            on_change(layout);
+        }
+    }
+}
+
+fn keyboard_layout(input: &Value) -> Option<KeyboardLayout> {
+    if input["type"] != "keyboard" {
+        return None;
+    }
+    Some(KeyboardLayout {
+        index: input["xkb_active_layout_index"].as_u64()?.try_into().ok()?,
+        name: input["xkb_active_layout_name"].as_str()?.to_owned(),
+    })
+}
+
+fn find_focused(node: &Value) -> Option<FocusedWindow> {
+    if node["focused"] == true {
+        return Some(focused_window(node));
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
+
+fn focused_window(container: &Value) -> FocusedWindow {
+    // native Wayland windows have an app ID, Xwayland ones an X11 class
+    let class = container["app_id"]
+        .as_str()
+        .or_else(|| container["window_properties"]["class"].as_str());
+    FocusedWindow {
+        class: class.unwrap_or_default().to_owned(),
+        title: container["name"].as_str().unwrap_or_default().to_owned(),
+    }
+}
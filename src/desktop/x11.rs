@@ -0,0 +1,164 @@
+//! X11 client based on the [EWMH](https://specifications.freedesktop.org/wm-spec/latest/) root window properties
+//! and the XKB extension
+
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{self, ConnectionExt as _},
+        xproto::{AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, EventMask, Window},
+        Event,
+    },
+    rust_connection::RustConnection,
+    NONE,
+};
+
+use super::{FocusedWindow, KeyboardLayout};
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        _NET_ACTIVE_WINDOW,
+        _NET_WM_NAME,
+        UTF8_STRING,
+    }
+}
+
+pub fn watch_focus(on_focus: &mut dyn FnMut(FocusedWindow)) -> anyhow::Result<()> {
+    let (conn, screen) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen].root;
+    let atoms = Atoms::new(&conn)?.reply()?;
+    conn.change_window_attributes(
+        root,
+        &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+    )?
+    .check()?;
+    let mut active = active_window(&conn, root, &atoms)?;
+    watch_title(&conn, active)?;
+    on_focus(focused_window(&conn, active, &atoms)?);
+    loop {
+        let Event::PropertyNotify(event) = conn.wait_for_event()? else {
+            // includes errors for windows destroyed before their properties were read
+            continue;
+        };
+        if event.window == root && event.atom == atoms._NET_ACTIVE_WINDOW {
+            let window = active_window(&conn, root, &atoms)?;
+            if window == active {
+                continue;
+            }
+            active = window;
+            watch_title(&conn, active)?;
+        } else if event.window != active
+            || (event.atom != atoms._NET_WM_NAME && event.atom != u32::from(AtomEnum::WM_NAME))
+        {
+            continue;
+        }
+        on_focus(focused_window(&conn, active, &atoms)?);
+    }
+}
+
+fn active_window(conn: &RustConnection, root: Window, atoms: &Atoms) -> anyhow::Result<Window> {
+    let reply = conn
+        .get_property(
+            false,
+            root,
+            atoms._NET_ACTIVE_WINDOW,
+            AtomEnum::WINDOW,
+            0,
+            1,
+        )?
+        .reply()?;
+    Ok(reply
+        .value32()
+        .and_then(|mut value| value.next())
+        .unwrap_or(NONE))
+}
+
+/// Get property change events of the window to follow its title
+fn watch_title(conn: &RustConnection, window: Window) -> anyhow::Result<()> {
+    if window != NONE {
+        conn.change_window_attributes(
+            window,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )?;
+    }
+    Ok(())
+}
+
+fn focused_window(
+    conn: &RustConnection,
+    window: Window,
+    atoms: &Atoms,
+) -> anyhow::Result<FocusedWindow> {
+    if window == NONE {
+        return Ok(FocusedWindow::default());
+    }
+    let property = |property, property_type| -> anyhow::Result<Vec<u8>> {
+        // a vanished window isn't an error, it just has no properties left
+        Ok(conn
+            .get_property(false, window, property, property_type, 0, 1024)?
+            .reply()
+            .map(|reply| reply.value)
+            .unwrap_or_default())
+    };
+    // WM_CLASS holds the null terminated instance and class names
+    let wm_class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into())?;
+    let class = wm_class.split(|byte| *byte == 0).nth(1).unwrap_or_default();
+    let mut title = property(atoms._NET_WM_NAME, atoms.UTF8_STRING)?;
+    if title.is_empty() {
+        title = property(AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+    }
+    Ok(FocusedWindow {
+        class: String::from_utf8_lossy(class).into_owned(),
+        title: String::from_utf8_lossy(&title).into_owned(),
+    })
+}
+
+pub fn watch_layout(on_change: &mut dyn FnMut(KeyboardLayout)) -> anyhow::Result<()> {
+    let (conn, _) = x11rb::connect(None)?;
+    if !conn.xkb_use_extension(1, 0)?.reply()?.supported {
+        anyhow::bail!("XKB extension not supported");
+    }
+    let device = xkb::ID::USE_CORE_KBD.into();
+    // only group changes, not every modifier press
+    conn.xkb_select_events(
+        device,
+        xkb::EventType::from(0u16),
+        xkb::EventType::from(0u16),
+        xkb::MapPart::from(0u16),
+        xkb::MapPart::from(0u16),
+        &xkb::SelectEventsAux::new().state_notify(xkb::SelectEventsAuxStateNotify {
+            affect_state: xkb::StatePart::GROUP_STATE,
+            state_details: xkb::StatePart::GROUP_STATE,
+        }),
+    )?
+    .check()?;
+    let group = conn.xkb_get_state(device)?.reply()?.group;
+    on_change(keyboard_layout(&conn, device, group.into())?);
+    loop {
+        if let Event::XkbStateNotify(event) = conn.wait_for_event()? {
+            on_change(keyboard_layout(&conn, device, event.group.into())?);
+        }
+    }
+}
+
+/// Layout of the given group, the group names are queried each time as `setxkbmap` can change them
+fn keyboard_layout(
+    conn: &RustConnection,
+    device: xkb::DeviceSpec,
+    group: u8,
+) -> anyhow::Result<KeyboardLayout> {
+    let names = conn
+        .xkb_get_names(device, xkb::NameDetail::GROUP_NAMES)?
+        .reply()?;
+    let name = match names
+        .value_list
+        .groups
+        .and_then(|groups| groups.get(usize::from(group)).copied())
+    {
+        Some(atom) if atom != NONE => conn.get_atom_name(atom)?.reply()?.name,
+        _ => Vec::new(),
+    };
+    Ok(KeyboardLayout {
+        index: group,
+        name: String::from_utf8_lossy(&name).into_owned(),
+    })
+}
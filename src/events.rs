@@ -0,0 +1,60 @@
+//! Keyboard arrivals, departures and messages, fanned out to every interested listener
+
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex, PoisonError,
+};
+
+use serde::Serialize;
+
+use crate::protocol::Message;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BoardEvent {
+    /// The keyboard was probed after being plugged in
+    Connected {
+        keeb: String,
+    },
+    Disconnected {
+        keeb: String,
+    },
+    /// The connected keyboard was probed again, e.g. after the host resumed, and may have lost
+    /// the host state it was sent before
+    Reprobed {
+        keeb: String,
+    },
+    /// A board -> host message other than an ack
+    Message {
+        keeb: String,
+        message: Message,
+    },
+}
+
+/// Subscribers of the board events, clones share the subscriber list
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<BoardEvent>>>>,
+}
+impl EventBus {
+    /// Receive every event published from now on, dropping the receiver unsubscribes
+    pub fn subscribe(&self) -> Receiver<BoardEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: BoardEvent) {
+        let mut subscribers = self.lock();
+        if subscribers.is_empty() {
+            tracing::debug!(?event, "No subscribers for the board event");
+        }
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Sender<BoardEvent>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
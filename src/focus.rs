@@ -0,0 +1,61 @@
+//! Switch keyboard layers based on the focused application
+
+use std::thread;
+
+use crate::{
+    config::FocusConfig,
+    connection::SharedConnection,
+    desktop::{self, FocusedWindow, WindowSystem},
+    forward::StateForwarder,
+    protocol::Message,
+    transport::HidTransport,
+};
+
+/// Watch the focused window in a background thread
+/// and send the layer of the first matching rule to the connected keyboards
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    let Some(window_system) = WindowSystem::detect() else {
+        tracing::warn!("No supported window system found, focus layers disabled");
+        return;
+    };
+    thread::spawn(move || {
+        // keyboards plugged in later get the layer of the focused window too,
+        // after the profile's default layer sent with the host OS
+        let forwarder = StateForwarder::new(connection.clone(), |_| true);
+        let result = desktop::watch_focus(window_system, &mut |window| {
+            let layer = connection
+                .lock()
+                .config()
+                .focus
+                .as_ref()
+                .and_then(|focus| layer_for(focus, &window));
+            let Some(layer) = layer else {
+                return;
+            };
+            tracing::trace!(
+                layer,
+                class = window.class,
+                title = window.title,
+                "Focus changed"
+            );
+            forwarder.send(Message::SetLayer { layer });
+        });
+        if let Err(err) = result {
+            tracing::error!(?window_system, "Focus watcher stopped: {err:#}");
+        }
+    });
+}
+
+fn layer_for(focus: &FocusConfig, window: &FocusedWindow) -> Option<u8> {
+    let matches = |pattern: &Option<String>, value: &str| {
+        pattern
+            .as_ref()
+            .is_none_or(|pattern| value.to_lowercase().contains(&pattern.to_lowercase()))
+    };
+    focus
+        .rules
+        .iter()
+        .find(|rule| matches(&rule.class, &window.class) && matches(&rule.title, &window.title))
+        .map(|rule| rule.layer)
+        .or(focus.default_layer)
+}
@@ -0,0 +1,130 @@
+//! Forward a watched host state to the keyboards, including the ones connecting later
+
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use crate::{
+    config::KeyboardConfig, connection::SharedConnection, events::BoardEvent, protocol::Message,
+    transport::HidTransport,
+};
+
+/// Last state of a host watcher, broadcast to the opted in keyboards when it changes
+/// and sent to each opted in keyboard that connects afterwards or is probed again after a resume
+pub struct StateForwarder<T: HidTransport> {
+    connection: SharedConnection<T>,
+    opted_in: fn(&KeyboardConfig) -> bool,
+    last_sent: Arc<Mutex<Option<Message>>>,
+}
+impl<T: HidTransport + 'static> StateForwarder<T> {
+    /// Start handing the last state to the keyboards as they (re)connect, in a background thread
+    pub fn new(connection: SharedConnection<T>, opted_in: fn(&KeyboardConfig) -> bool) -> Self {
+        let last_sent = Arc::new(Mutex::new(None));
+        let events = connection.lock().events().subscribe();
+        {
+            let connection = connection.clone();
+            let last_sent = last_sent.clone();
+            thread::spawn(move || {
+                for event in events {
+                    let (BoardEvent::Connected { keeb } | BoardEvent::Reprobed { keeb }) = event
+                    else {
+                        continue;
+                    };
+                    let state = last_sent
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone();
+                    let Some(state) = state else {
+                        continue;
+                    };
+                    let mut connection = connection.lock();
+                    if !connection
+                        .config()
+                        .keyboards
+                        .get(&keeb)
+                        .is_some_and(opted_in)
+                    {
+                        continue;
+                    }
+                    if let Err(err) = connection.send(&keeb, &state) {
+                        tracing::warn!(keeb, ?state, "Failed to send: {err:#}");
+                    }
+                }
+            });
+        }
+        Self {
+            connection,
+            opted_in,
+            last_sent,
+        }
+    }
+
+    /// Broadcast the state unless it's the one sent last, watchers often report the same state again
+    pub fn send(&self, state: Message) {
+        let mut last_sent = self
+            .last_sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last_sent.as_ref() == Some(&state) {
+            return;
+        }
+        tracing::debug!(?state, "Host state changed");
+        self.connection
+            .lock()
+            .broadcast_where(self.opted_in, &state);
+        *last_sent = Some(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        protocol,
+        transport::{DeviceInfo, MockTransport, WrittenReport, MOCK_KEYBOARD, MOCK_USB},
+        BoardConnection, Config,
+    };
+
+    #[test]
+    fn late_keyboards_get_the_last_state() {
+        let transport = MockTransport::new();
+        let config: Config = toml::from_str(&format!("{MOCK_KEYBOARD}volume = true")).unwrap();
+        let connection =
+            SharedConnection::new(BoardConnection::with_transport(transport.clone(), config));
+        let forwarder = StateForwarder::new(connection.clone(), |keeb_config| keeb_config.volume);
+        let volume = Message::Volume {
+            percent: 40,
+            muted: false,
+        };
+        forwarder.send(volume.clone());
+        assert!(transport.written().is_empty());
+
+        transport.attach(DeviceInfo::mock("mock-0"));
+        connection.probe_with_retry("klor", MOCK_USB);
+        let expected = protocol::encode(&volume).unwrap();
+        let written = await_writes(&transport, 2);
+        assert_eq!(written.len(), 2, "host OS then the volume");
+        assert_eq!(written[1].data[1..=expected[0].len()], expected[0]);
+
+        // the same state again isn't resent
+        forwarder.send(volume);
+        assert_eq!(transport.written().len(), 2);
+
+        // but it is after a resume, the board may have lost it
+        connection.reprobe_connected();
+        let written = await_writes(&transport, 4);
+        assert_eq!(written.len(), 4, "host OS then the volume again");
+        assert_eq!(written[3].data[1..=expected[0].len()], expected[0]);
+    }
+
+    fn await_writes(transport: &MockTransport, count: usize) -> Vec<WrittenReport> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while transport.written().len() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        transport.written()
+    }
+}
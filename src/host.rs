@@ -0,0 +1,106 @@
+//! Host OS and descriptors reported to the keyboards
+
+use serde::Deserialize;
+
+use crate::{config::HostConfig, protocol::Message};
+
+/// [QMK `os_variant_t`](https://github.com/qmk/qmk_firmware/blob/26f898c8a538b808cf506f558a9454f7f50e3ba6/quantum/os_detection.h#L23)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsVariant {
+    Unsure = 0,
+    Linux = 1,
+    Windows = 2,
+    Macos = 3,
+    Ios = 4,
+}
+impl OsVariant {
+    /// OS the binary was built for
+    pub fn detect() -> Self {
+        if cfg!(target_os = "linux") {
+            OsVariant::Linux
+        } else if cfg!(target_os = "windows") {
+            OsVariant::Windows
+        } else if cfg!(target_os = "macos") {
+            OsVariant::Macos
+        } else if cfg!(target_os = "ios") {
+            OsVariant::Ios
+        } else {
+            OsVariant::Unsure
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Host OS and descriptors, detected once and overridden by the `[host]` config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    pub os: OsVariant,
+    /// Clamped to a byte, 0 when unknown (e.g. rolling release distros)
+    pub os_version_major: u8,
+    pub os_version_minor: u8,
+    /// `ID` from `os-release`, e.g. `fedora`, empty outside of Linux
+    pub distro_id: String,
+    /// First `XDG_CURRENT_DESKTOP` entry, e.g. `GNOME`
+    pub desktop: String,
+    pub hostname: String,
+    /// ID of the matching `[profiles.<hostname>]`, 0 without one
+    pub profile_id: u8,
+}
+impl HostInfo {
+    /// The profile ID is left at 0, it's only known once the hostname is
+    pub fn detect(config: &HostConfig) -> Self {
+        let (major, minor) = match os_info::get().version() {
+            os_info::Version::Semantic(major, minor, _) => (*major, *minor),
+            _ => (0, 0),
+        };
+        let byte = |part: u64| part.min(u8::MAX.into()) as u8;
+        Self {
+            os: config.os.unwrap_or_else(OsVariant::detect),
+            os_version_major: byte(major),
+            os_version_minor: byte(minor),
+            distro_id: config.distro_id.clone().unwrap_or_else(distro_id),
+            desktop: config.desktop.clone().unwrap_or_else(desktop),
+            hostname: config.hostname.clone().unwrap_or_else(hostname),
+            profile_id: 0,
+        }
+    }
+
+    pub fn message(&self) -> Message {
+        Message::host_info(&self.distro_id, &self.desktop, &self.hostname)
+    }
+}
+
+fn distro_id() -> String {
+    ["/etc/os-release", "/usr/lib/os-release"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .and_then(|os_release| {
+            os_release.lines().find_map(|line| {
+                line.strip_prefix("ID=")
+                    .map(|id| id.trim_matches(['"', '\'']).to_owned())
+            })
+        })
+        .unwrap_or_default()
+}
+
+fn desktop() -> String {
+    std::env::var("XDG_CURRENT_DESKTOP")
+        .ok()
+        .and_then(|desktops| desktops.split(':').next().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+fn hostname() -> String {
+    #[cfg(target_os = "linux")]
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").ok();
+    #[cfg(not(target_os = "linux"))]
+    let hostname = std::process::Command::new("hostname")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    hostname.unwrap_or_default().trim().to_owned()
+}
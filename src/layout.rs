@@ -0,0 +1,32 @@
+//! Follow the host keyboard layout so the boards can match their indicators and unicode macros
+//!
+//! Caps, Num and Scroll Lock aren't forwarded, the host already sets them
+//! through the standard HID LED report the firmware reads.
+
+use std::thread;
+
+use crate::{
+    connection::SharedConnection,
+    desktop::{self, LayoutSource},
+    forward::StateForwarder,
+    protocol::Message,
+    transport::HidTransport,
+};
+
+/// Watch the active keyboard layout in a background thread
+/// and send it to the keyboards with `layout` enabled
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    let Some(source) = LayoutSource::detect() else {
+        tracing::warn!("No supported desktop found, layout sync disabled");
+        return;
+    };
+    thread::spawn(move || {
+        let forwarder = StateForwarder::new(connection, |keeb_config| keeb_config.layout);
+        let result = desktop::watch_layout(source, &mut |layout| {
+            forwarder.send(Message::layout(layout.index, &layout.name));
+        });
+        if let Err(err) = result {
+            tracing::error!(?source, "Layout watcher stopped: {err:#}");
+        }
+    });
+}
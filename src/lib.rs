@@ -0,0 +1,44 @@
+#[cfg(target_os = "linux")]
+pub mod actions;
+mod config;
+#[cfg(target_os = "linux")]
+pub mod config_watch;
+mod connection;
+#[cfg(unix)]
+pub mod control;
+#[cfg(target_os = "linux")]
+pub mod dbus;
+#[cfg(target_os = "linux")]
+pub mod desktop;
+pub mod events;
+#[cfg(target_os = "linux")]
+pub mod focus;
+pub mod forward;
+pub mod host;
+#[cfg(target_os = "linux")]
+pub mod layout;
+pub mod logging;
+#[cfg(target_os = "linux")]
+pub mod mpris;
+pub mod payload;
+pub mod protocol;
+pub mod registry;
+pub mod session;
+#[cfg(target_os = "linux")]
+pub mod session_state;
+#[cfg(target_os = "linux")]
+pub mod sleep;
+#[cfg(target_os = "linux")]
+pub mod stats;
+#[cfg(all(test, target_os = "linux"))]
+mod test_bus;
+pub mod time_sync;
+pub mod transport;
+#[cfg(target_os = "linux")]
+pub mod volume;
+
+pub use config::{
+    ActionConfig, Config, FocusConfig, FocusRule, HandshakeConfig, HostConfig, KeyboardConfig,
+    ProfileConfig, Protocol, RetryConfig, StatsConfig, TimeSyncConfig,
+};
+pub use connection::{BoardConnection, SharedConnection};
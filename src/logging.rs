@@ -0,0 +1,73 @@
+//! Log output to stderr, or to the systemd journal when running as a service
+
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+/// Level used when neither the CLI, `RUST_LOG` nor the config set one
+const DEFAULT_FILTER: &str = "info";
+
+/// Handle to the installed filter for applying the `log_level` of a reloaded config
+pub struct LogFilter {
+    /// `None` when the CLI or `RUST_LOG` set the filter, those take precedence over the config
+    config_handle: Option<reload::Handle<EnvFilter, Registry>>,
+}
+
+impl LogFilter {
+    /// Switch to the config filter, or the default `info` when the config has none
+    pub fn set_config(&self, config_filter: Option<&str>) -> anyhow::Result<()> {
+        if let Some(handle) = &self.config_handle {
+            handle.reload(EnvFilter::try_new(config_filter.unwrap_or(DEFAULT_FILTER))?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Install the global subscriber, the filter is taken from the first of
+/// the CLI, the `RUST_LOG` env variable, the config and [`DEFAULT_FILTER`] that's set
+///
+/// Filters use the [`EnvFilter`] syntax, e.g. `debug` or `keeb_os_probe::connection=trace`.
+pub fn init(cli_filter: Option<&str>, config_filter: Option<&str>) -> anyhow::Result<LogFilter> {
+    let (filter, from_config) = match (cli_filter, std::env::var(EnvFilter::DEFAULT_ENV).ok()) {
+        (Some(filter), _) => (EnvFilter::try_new(filter)?, false),
+        (None, Some(filter)) => (EnvFilter::try_new(filter)?, false),
+        (None, None) => (
+            EnvFilter::try_new(config_filter.unwrap_or(DEFAULT_FILTER))?,
+            true,
+        ),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(output().with_filter(filter))
+        .try_init()?;
+    Ok(LogFilter {
+        config_handle: from_config.then_some(handle),
+    })
+}
+
+/// systemd sets `JOURNAL_STREAM` when a service's stderr is connected to the journal,
+/// native journald records keep the event fields filterable, e.g. `journalctl --user KEEB=klor`
+#[cfg(target_os = "linux")]
+fn output<S>() -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    if std::env::var_os("JOURNAL_STREAM").is_some() {
+        if let Ok(journald) = tracing_journald::layer() {
+            return journald.boxed();
+        }
+    }
+    tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .boxed()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn output<S>() -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .boxed()
+}
@@ -3,109 +3,371 @@
     windows_subsystem = "windows"
 )]
 
-use std::{collections::HashMap, fs, thread, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
 
 use anyhow::Context;
+use clap::{Parser, Subcommand};
+use keeb_os_probe::{
+    logging::LogFilter,
+    registry::BoardState,
+    transport::{HidApiTransport, HidTransport},
+    BoardConnection, Config, SharedConnection,
+};
 use rusb::UsbContext;
-use serde::Deserialize;
 
-const HID_USAGE: u16 = 0x61;
-const HID_USAGE_PAGE: u16 = 0xFF60;
+/// How often the hotplug loop checks for config reloads
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-/// [QMK OS enum](https://github.com/qmk/qmk_firmware/blob/26f898c8a538b808cf506f558a9454f7f50e3ba6/quantum/os_detection.h#L23)
-#[cfg(target_os = "linux")]
-const HOST_OS_CODE: u8 = 1;
-#[cfg(target_os = "windows")]
-const HOST_OS_CODE: u8 = 2;
-#[cfg(target_os = "macos")]
-const HOST_OS_CODE: u8 = 3;
+/// Send the host OS (and more) to QMK keyboards over raw HID
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file, defaults to `keeb_os_probe.toml` in the local config dir
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Log filter, e.g. `debug` or `keeb_os_probe::connection=trace`,
+    /// takes precedence over `RUST_LOG` and the config
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Probe the configured keyboards as they're plugged in (the default)
+    Run,
+    /// List the connected HID devices to find the values for the config
+    List,
+    /// Send the host OS to a configured keyboard once
+    Probe { name: String },
+    /// Send a raw packet to a configured keyboard, e.g. `send klor 2a 01` or `send klor 0x2a01`
+    Send {
+        name: String,
+        #[arg(required = true)]
+        bytes: Vec<String>,
+    },
+    /// Validate the config file
+    CheckConfig,
+    /// Show the keyboards connected to the running daemon
+    Status,
+    /// Talk to the running daemon through its control socket, printing the JSON responses
+    #[command(subcommand)]
+    Client(ClientCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum ClientCommand {
+    /// List the connected keyboards
+    List,
+    /// Send a raw packet through the daemon, e.g. `client send klor 2a 01`
+    Send {
+        name: String,
+        #[arg(required = true)]
+        bytes: Vec<String>,
+    },
+    /// Switch the layer of a keyboard, or of all connected ones
+    SetLayer {
+        layer: u8,
+        #[arg(long)]
+        keeb: Option<String>,
+    },
+    /// Print the keyboard arrivals, departures and messages as they happen
+    Subscribe,
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config_path = match cli.config {
+        Some(path) => path,
+        None => Config::default_path()?,
+    };
+    let command = cli.command.unwrap_or(Command::Run);
+    let config = match command {
+        Command::List | Command::Status | Command::Client(_) => None,
+        _ => Some(Config::load(&config_path)?),
+    };
+    let log_filter = keeb_os_probe::logging::init(
+        cli.log_level.as_deref(),
+        config
+            .as_ref()
+            .and_then(|config| config.log_level.as_deref()),
+    )?;
+    match (command, config) {
+        (Command::List, _) => list(),
+        (Command::Status, _) => status(),
+        (Command::Client(command), _) => client(command),
+        (_, None) => unreachable!("The config is loaded for all other commands"),
+        (Command::Run, Some(config)) => run(&config_path, config, &log_filter),
+        (Command::Probe { name }, Some(config)) => {
+            let mut connection = BoardConnection::new(config)?;
+            if !connection.probe_keeb(&name)? {
+                anyhow::bail!("Keeb '{name}' not connected");
+            }
+            Ok(())
+        }
+        (Command::Send { name, bytes }, Some(config)) => {
+            let packet = parse_hex(&bytes)?;
+            let mut connection = BoardConnection::new(config)?;
+            if !connection.send_raw(&name, &packet)? {
+                anyhow::bail!("Keeb '{name}' not connected");
+            }
+            Ok(())
+        }
+        (Command::CheckConfig, Some(config)) => {
+            let mut keebs: Vec<_> = config.keyboards.keys().collect();
+            keebs.sort();
+            println!("{config_path:?} is valid, keyboards: {keebs:?}");
+            Ok(())
+        }
+    }
+}
 
 /// Try to connect to the configured HID device(s)
 /// and send HID messages passing the current host OS code
-pub fn main() -> anyhow::Result<()> {
+fn run(config_path: &Path, config: Config, log_filter: &LogFilter) -> anyhow::Result<()> {
     if !rusb::has_hotplug() {
         anyhow::bail!("No hotplug compat");
     }
-    let mut config_path = dirs::config_local_dir().context("Could not find config path")?;
-    config_path.push("keeb_os_probe.toml");
-    let config_toml =
-        fs::read_to_string(&config_path).context(format!("Config path: {:?}", &config_path))?;
-    let config: Config = toml::from_str(&config_toml)?;
-    if config.keyboards.is_empty() {
-        anyhow::bail!("No boards configured");
-    }
+    let mut keebs = keeb_ids(&config);
     let context = rusb::Context::new()?;
+    let connection = SharedConnection::new(BoardConnection::new(config)?);
+    #[cfg(unix)]
+    keeb_os_probe::control::spawn(connection.clone())?;
+    let mut _registration = register_hotplug(&context, &connection)?;
+    spawn_watchers(&connection);
+    let (reload_sender, reloads) = mpsc::channel();
+    #[cfg(target_os = "linux")]
+    keeb_os_probe::config_watch::spawn(config_path.to_owned(), reload_sender)?;
+    #[cfg(not(target_os = "linux"))]
+    drop(reload_sender);
+    loop {
+        context.handle_events(Some(RELOAD_POLL_INTERVAL))?;
+        for config in reloads.try_iter() {
+            let reloaded_keebs = keeb_ids(&config);
+            if let Err(err) = log_filter.set_config(config.log_level.as_deref()) {
+                tracing::warn!("Failed to apply the reloaded log level: {err:#}");
+            }
+            connection.lock().set_config(config);
+            if reloaded_keebs == keebs {
+                tracing::info!("Config reloaded");
+                continue;
+            }
+            keebs = reloaded_keebs;
+            // replacing the registration drops the old filter,
+            // the new one probes the already connected boards
+            _registration = register_hotplug(&context, &connection)?;
+            tracing::info!(?keebs, "Config reloaded, keyboards changed");
+        }
+    }
+}
+
+fn register_hotplug(
+    context: &rusb::Context,
+    connection: &SharedConnection<HidApiTransport>,
+) -> anyhow::Result<rusb::Registration<rusb::Context>> {
     let mut hotplug = rusb::HotplugBuilder::new();
-    if config.keyboards.len() == 1 {
-        // limit hotplug to the single device vendor & product IDs
-        let (_, keeb_conf) = &config.keyboards.iter().next().unwrap();
-        hotplug
-            .vendor_id(keeb_conf.vendor_id)
-            .product_id(keeb_conf.product_id);
+    {
+        let connection = connection.lock();
+        let keyboards = &connection.config().keyboards;
+        if keyboards.len() == 1 {
+            // limit hotplug to the single device vendor & product IDs
+            let (_, keeb_conf) = keyboards.iter().next().unwrap();
+            hotplug
+                .vendor_id(keeb_conf.vendor_id)
+                .product_id(keeb_conf.product_id);
+        }
     }
-    let _reg = hotplug
+    // the connection must be unlocked here, the enumeration probes the boards right away
+    Ok(hotplug
         .enumerate(true)
-        .register::<rusb::Context, _>(&context, Box::new(BoardConnection::new(config)?))?;
-    loop {
-        context.handle_events(None)?;
+        .register::<rusb::Context, _>(context, Box::new(connection.clone()))?)
+}
+
+/// Configured keyboards as far as the hotplug filter is concerned
+fn keeb_ids(config: &Config) -> Vec<(String, u16, u16)> {
+    let mut keebs: Vec<_> = config
+        .keyboards
+        .iter()
+        .map(|(keeb, keeb_config)| (keeb.clone(), keeb_config.vendor_id, keeb_config.product_id))
+        .collect();
+    keebs.sort();
+    keebs
+}
+
+/// Start the host watchers enabled in the config
+///
+/// Watchers only read the config when they act, so they follow reloads,
+/// but a watcher enabled by a reload only starts with the next daemon start.
+fn spawn_watchers<T: HidTransport + 'static>(connection: &SharedConnection<T>) {
+    let (time_sync, stats, now_playing, layout, volume, session_state, focus, actions) = {
+        let connection = connection.lock();
+        let config = connection.config();
+        let keebs = || config.keyboards.values();
+        (
+            keebs().any(|keeb_config| keeb_config.time_sync.is_some()),
+            keebs().any(|keeb_config| keeb_config.stats),
+            keebs().any(|keeb_config| keeb_config.now_playing),
+            keebs().any(|keeb_config| keeb_config.layout),
+            keebs().any(|keeb_config| keeb_config.volume),
+            keebs().any(|keeb_config| keeb_config.session_state),
+            config.focus.is_some(),
+            keebs().any(|keeb_config| !keeb_config.actions.is_empty()),
+        )
+    };
+    if time_sync {
+        keeb_os_probe::time_sync::spawn(connection.clone());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let service_bus = keeb_os_probe::dbus::spawn(connection.clone());
+        // any board may lose the host OS over a suspend
+        keeb_os_probe::sleep::spawn(connection.clone());
+        if stats {
+            keeb_os_probe::stats::spawn(connection.clone());
+        }
+        if now_playing {
+            keeb_os_probe::mpris::spawn(connection.clone());
+        }
+        if layout {
+            keeb_os_probe::layout::spawn(connection.clone());
+        }
+        if volume {
+            keeb_os_probe::volume::spawn(connection.clone());
+        }
+        if session_state {
+            keeb_os_probe::session_state::spawn(connection.clone());
+        }
+        if focus {
+            keeb_os_probe::focus::spawn(connection.clone());
+        }
+        if actions {
+            keeb_os_probe::actions::spawn(connection.clone(), service_bus);
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    keyboards: HashMap<String, KeyboardConfig>,
+fn list() -> anyhow::Result<()> {
+    let mut devices = HidApiTransport::new()?.devices()?;
+    devices.sort_by_key(|device| (device.vendor_id, device.product_id, device.usage_page));
+    println!("VID     PID     USAGE_PAGE  USAGE   INTERFACE  PATH");
+    for device in devices {
+        println!(
+            "{:#06x}  {:#06x}  {:#06x}      {:#06x}  {:<9}  {}",
+            device.vendor_id,
+            device.product_id,
+            device.usage_page,
+            device.usage,
+            device.interface_number,
+            device.path.to_string_lossy()
+        );
+        println!(
+            "        manufacturer {:?}, product {:?}, serial number {:?}",
+            device.manufacturer.unwrap_or_default(),
+            device.product.unwrap_or_default(),
+            device.serial_number.unwrap_or_default()
+        );
+    }
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct KeyboardConfig {
-    vendor_id: u16,
-    product_id: u16,
+#[cfg(unix)]
+fn client(command: ClientCommand) -> anyhow::Result<()> {
+    let request = match command {
+        ClientCommand::List => serde_json::json!({ "cmd": "list" }),
+        ClientCommand::Send { name, bytes } => {
+            serde_json::json!({ "cmd": "send", "keeb": name, "bytes": parse_hex(&bytes)? })
+        }
+        ClientCommand::SetLayer { layer, keeb } => {
+            serde_json::json!({ "cmd": "set_layer", "keeb": keeb, "layer": layer })
+        }
+        ClientCommand::Subscribe => serde_json::json!({ "cmd": "subscribe" }),
+    };
+    keeb_os_probe::control::request(&request, &mut |response| println!("{response}"))
 }
 
-struct BoardConnection {
-    hid_api: hidapi::HidApi,
-    config: Config,
+#[cfg(not(unix))]
+fn client(_command: ClientCommand) -> anyhow::Result<()> {
+    anyhow::bail!("The control socket is only available on unix")
+}
+
+#[cfg(unix)]
+fn daemon_boards() -> anyhow::Result<Vec<BoardState>> {
+    let mut response = serde_json::Value::Null;
+    keeb_os_probe::control::request(&serde_json::json!({ "cmd": "list" }), &mut |line| {
+        response = line
+    })?;
+    Ok(serde_json::from_value(response["boards"].take())?)
+}
+
+#[cfg(not(unix))]
+fn daemon_boards() -> anyhow::Result<Vec<BoardState>> {
+    anyhow::bail!("The control socket is only available on unix")
 }
-impl BoardConnection {
-    pub fn new(config: Config) -> anyhow::Result<Self> {
-        Ok(Self {
-            hid_api: hidapi::HidApi::new()?,
-            config,
-        })
-    }
 
-    pub fn probe(&self, vendor_id: u16, product_id: u16) -> anyhow::Result<()> {
-        if let Some((keeb, keeb_config)) = &self.config.keyboards.iter().find(|(_, keeb_config)| {
-            keeb_config.vendor_id == vendor_id && keeb_config.product_id == product_id
-        }) {
-            thread::sleep(Duration::from_millis(50));
-            let Some(device) = self.hid_api.device_list().find(|device| {
-                device.vendor_id() == keeb_config.vendor_id
-                    && device.product_id() == keeb_config.product_id
-                    && device.usage() == HID_USAGE
-                    && device.usage_page() == HID_USAGE_PAGE
-            }) else {
-                eprintln!("Keeb '{keeb}' not connected");
-                return Ok(());
-            };
-            let device = self.hid_api.open_path(device.path())?;
-            device.write(&[
-                0, // report ID - mandatory
-                // the actual payload starts here, limited to 32 bytes in QMK (or by HID in general?)
-                42, // reporting host
-                HOST_OS_CODE,
-            ])?;
-        }
-        Ok(())
+fn status() -> anyhow::Result<()> {
+    let boards = daemon_boards()?;
+    if boards.is_empty() {
+        println!("No keyboards connected");
+        return Ok(());
     }
+    let time = |unix_secs: u64| {
+        chrono::DateTime::from_timestamp(unix_secs.try_into().unwrap_or_default(), 0)
+            .map(|time| {
+                time.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    };
+    println!("KEEB          USB      ARRIVED              LAST MESSAGE               PATHS");
+    for board in boards {
+        let last_message = board
+            .last_message
+            .map(|sent| format!("{:#04x} {}", sent.command, time(sent.sent_at)))
+            .unwrap_or_else(|| "-".to_owned());
+        println!(
+            "{:<12}  {:03}:{:03}  {}  {:<25}  {}",
+            board.keeb,
+            board.usb.bus,
+            board.usb.address,
+            time(board.arrived_at),
+            last_message,
+            board.paths.join(", ")
+        );
+    }
+    Ok(())
 }
-impl<T: rusb::UsbContext> rusb::Hotplug<T> for BoardConnection {
-    fn device_arrived(&mut self, device: rusb::Device<T>) {
-        if let Ok(desc) = device.device_descriptor() {
-            self.probe(desc.vendor_id(), desc.product_id())
-                .expect("Probed device");
+
+/// Parse `0x`-prefixed single bytes or runs of hex digit pairs, separated by spaces or commas
+fn parse_hex(args: &[String]) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for token in args
+        .iter()
+        .flat_map(|arg| arg.split([' ', ',']))
+        .filter(|token| !token.is_empty())
+    {
+        let invalid = || format!("Invalid hex byte(s) '{token}'");
+        if let Some(digits) = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .filter(|digits| digits.len() <= 2)
+        {
+            bytes.push(u8::from_str_radix(digits, 16).with_context(invalid)?);
+            continue;
+        }
+        let digits = token.trim_start_matches("0x").trim_start_matches("0X");
+        if digits.len() % 2 != 0 {
+            anyhow::bail!(invalid());
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).with_context(invalid)?;
+            bytes.push(u8::from_str_radix(pair, 16).with_context(invalid)?);
         }
     }
-
-    fn device_left(&mut self, _device: rusb::Device<T>) {}
+    Ok(bytes)
 }
@@ -0,0 +1,326 @@
+//! Forward the "now playing" state of [MPRIS](https://specifications.freedesktop.org/mpris-spec/latest/) media players
+
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use zbus::{
+    blocking::{fdo::DBusProxy, Connection, MessageIterator},
+    message::Type,
+    proxy::CacheProperties,
+    zvariant::OwnedValue,
+    MatchRule,
+};
+
+use crate::{
+    connection::SharedConnection,
+    forward::StateForwarder,
+    protocol::{Message, PlaybackStatus},
+    transport::HidTransport,
+};
+
+const PLAYER_BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+
+#[zbus::proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_path = "/org/mpris/MediaPlayer2",
+    gen_async = false
+)]
+trait Player {
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn metadata(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+}
+
+#[derive(Debug)]
+enum Change {
+    /// Playback status or metadata of the player with this unique name
+    Properties(String),
+    /// A player name was taken by this unique name
+    Appeared(String),
+    /// A player name was released by this unique name, e.g. because the player quit
+    Gone(String),
+}
+
+/// Block the current thread, calling `on_change` with the state of the playing player, if any,
+/// and then with the state of whichever player last changed its playback status or metadata
+///
+/// When the reported player goes away, the state of another playing player is sent instead,
+/// or [`PlaybackStatus::Stopped`] if there is none.
+pub fn watch(bus: &Connection, on_change: &mut dyn FnMut(Message)) -> anyhow::Result<()> {
+    let properties_rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(PLAYER_PATH)?
+        .arg(0, PLAYER_INTERFACE)?
+        .build();
+    let owners_rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns(PLAYER_BUS_PREFIX.trim_end_matches('.'))?
+        .build();
+    let properties = MessageIterator::for_match_rule(properties_rule, bus, None)?;
+    let owners = MessageIterator::for_match_rule(owners_rule, bus, None)?;
+    let (changes, received) = mpsc::channel();
+    {
+        let changes = changes.clone();
+        thread::spawn(move || {
+            if let Err(err) = forward_properties(properties, &changes) {
+                tracing::warn!("Media player property watcher stopped: {err:#}");
+            }
+        });
+    }
+    thread::spawn(move || {
+        if let Err(err) = forward_owners(owners, &changes) {
+            tracing::warn!("Media player name watcher stopped: {err:#}");
+        }
+    });
+
+    // by unique name, so the property signals can be told apart
+    let mut players = HashMap::new();
+    let dbus = DBusProxy::new(bus)?;
+    for name in dbus.list_names()? {
+        if !name.starts_with(PLAYER_BUS_PREFIX) {
+            continue;
+        }
+        // one misbehaving player shouldn't keep the others from being watched
+        let state = dbus
+            .get_name_owner(name.as_ref())
+            .map_err(anyhow::Error::from)
+            .and_then(|owner| Ok((owner.to_string(), player_state(bus, owner.as_str())?)));
+        match state {
+            Ok((owner, state)) => {
+                players.insert(owner, state);
+            }
+            Err(err) => tracing::warn!(player = %name, "Failed to query media player: {err:#}"),
+        }
+    }
+    let mut reported = playing(&players);
+    if let Some(owner) = &reported {
+        on_change(players[owner].clone());
+    }
+    for change in received {
+        tracing::trace!(?change, "Media player change");
+        match change {
+            // the signal only carries the changed properties, so query the full state
+            Change::Properties(owner) => match player_state(bus, &owner) {
+                Ok(state) => {
+                    on_change(state.clone());
+                    players.insert(owner.clone(), state);
+                    reported = Some(owner);
+                }
+                Err(err) => tracing::warn!(player = owner, "Failed to query media player: {err:#}"),
+            },
+            Change::Appeared(owner) => match player_state(bus, &owner) {
+                Ok(state) => {
+                    players.insert(owner, state);
+                }
+                Err(err) => tracing::warn!(player = owner, "Failed to query media player: {err:#}"),
+            },
+            Change::Gone(owner) => {
+                players.remove(&owner);
+                if reported.as_ref() != Some(&owner) {
+                    continue;
+                }
+                reported = playing(&players);
+                on_change(match &reported {
+                    Some(owner) => players[owner].clone(),
+                    None => Message::now_playing(PlaybackStatus::Stopped, "", ""),
+                });
+            }
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+fn forward_properties(signals: MessageIterator, changes: &Sender<Change>) -> anyhow::Result<()> {
+    for signal in signals {
+        if let Some(sender) = signal?.header().sender() {
+            changes.send(Change::Properties(sender.to_string()))?;
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+fn forward_owners(signals: MessageIterator, changes: &Sender<Change>) -> anyhow::Result<()> {
+    for signal in signals {
+        let (name, old_owner, new_owner): (String, String, String) =
+            signal?.body().deserialize()?;
+        if !name.starts_with(PLAYER_BUS_PREFIX) {
+            continue;
+        }
+        if !old_owner.is_empty() {
+            changes.send(Change::Gone(old_owner))?;
+        }
+        if !new_owner.is_empty() {
+            changes.send(Change::Appeared(new_owner))?;
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+/// The unique name of a player that is playing, if any
+fn playing(players: &HashMap<String, Message>) -> Option<String> {
+    players
+        .iter()
+        .find(|(_, state)| {
+            matches!(
+                state,
+                Message::NowPlaying {
+                    status: PlaybackStatus::Playing,
+                    ..
+                }
+            )
+        })
+        .map(|(owner, _)| owner.clone())
+}
+
+fn player_state(bus: &Connection, destination: &str) -> anyhow::Result<Message> {
+    let player = PlayerProxy::builder(bus)
+        .destination(destination.to_owned())?
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    let status = match player.playback_status()?.as_str() {
+        "Playing" => PlaybackStatus::Playing,
+        "Paused" => PlaybackStatus::Paused,
+        _ => PlaybackStatus::Stopped,
+    };
+    let metadata = player.metadata()?;
+    let title = metadata
+        .get("xesam:title")
+        .and_then(|title| String::try_from(title.try_clone().ok()?).ok())
+        .unwrap_or_default();
+    let artist = metadata
+        .get("xesam:artist")
+        .and_then(|artist| Vec::<String>::try_from(artist.try_clone().ok()?).ok())
+        .unwrap_or_default()
+        .join(", ");
+    Ok(Message::now_playing(status, &title, &artist))
+}
+
+/// Watch the session bus media players and send their state to the keyboards with `now_playing` enabled
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let forwarder = StateForwarder::new(connection, |keeb_config| keeb_config.now_playing);
+        let result = Connection::session()
+            .map_err(anyhow::Error::from)
+            .and_then(|bus| watch(&bus, &mut |state| forwarder.send(state)));
+        if let Err(err) = result {
+            tracing::error!("Media player watcher stopped: {err:#}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, Receiver},
+        time::Duration,
+    };
+
+    use zbus::{blocking::object_server::InterfaceRef, zvariant::Value};
+
+    use super::*;
+    use crate::test_bus::PrivateBus;
+
+    struct FakePlayer {
+        status: String,
+        title: String,
+    }
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl FakePlayer {
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.clone()
+        }
+
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            HashMap::from([
+                (
+                    "xesam:title".to_owned(),
+                    Value::from(self.title.as_str()).try_into().unwrap(),
+                ),
+                (
+                    "xesam:artist".to_owned(),
+                    Value::from(vec!["Radiohead"]).try_into().unwrap(),
+                ),
+            ])
+        }
+    }
+
+    fn watch_in_background(bus: Connection) -> Receiver<Message> {
+        let (states, received) = mpsc::channel();
+        thread::spawn(move || {
+            watch(&bus, &mut |state| {
+                let _ = states.send(state);
+            })
+        });
+        received
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn follows_a_player_until_it_quits_and_skips_a_broken_one() {
+        let bus = PrivateBus::start();
+        // owns a player name but answers every call with UnknownObject
+        let broken = bus.connect();
+        broken.object_server();
+        broken
+            .request_name("org.mpris.MediaPlayer2.broken")
+            .unwrap();
+        let player_bus = bus.connect();
+        player_bus
+            .object_server()
+            .at(
+                PLAYER_PATH,
+                FakePlayer {
+                    status: "Playing".to_owned(),
+                    title: "Paranoid Android".to_owned(),
+                },
+            )
+            .unwrap();
+        player_bus
+            .request_name("org.mpris.MediaPlayer2.fake")
+            .unwrap();
+
+        let states = watch_in_background(bus.connect());
+        assert_eq!(
+            states.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::now_playing(PlaybackStatus::Playing, "Paranoid Android", "Radiohead")
+        );
+
+        let player: InterfaceRef<FakePlayer> =
+            player_bus.object_server().interface(PLAYER_PATH).unwrap();
+        player.get_mut().status = "Paused".to_owned();
+        zbus::block_on(
+            player
+                .get()
+                .playback_status_changed(player.signal_emitter()),
+        )
+        .unwrap();
+        assert_eq!(
+            states.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::now_playing(PlaybackStatus::Paused, "Paranoid Android", "Radiohead")
+        );
+
+        // the player quits without resetting its status
+        player_bus
+            .release_name("org.mpris.MediaPlayer2.fake")
+            .unwrap();
+        assert_eq!(
+            states.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::now_playing(PlaybackStatus::Stopped, "", "")
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Custom host report payloads for firmware that doesn't speak the framed protocol
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::host::HostInfo;
+
+/// Bytes of a custom host report, e.g. `["0x2A", "{os_code}", "{os_version_major}"]`
+///
+/// Each entry is a byte literal (decimal or `0x` prefixed hex) or a placeholder
+/// resolved when the report is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct PayloadTemplate(Vec<PayloadByte>);
+impl PayloadTemplate {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the profile ID of the host is sent
+    pub fn has_profile_id(&self) -> bool {
+        self.0.contains(&PayloadByte::ProfileId)
+    }
+
+    pub fn render(&self, host: &HostInfo) -> Vec<u8> {
+        self.0
+            .iter()
+            .map(|byte| match byte {
+                PayloadByte::Literal(value) => *value,
+                PayloadByte::OsCode => host.os.code(),
+                PayloadByte::OsVersionMajor => host.os_version_major,
+                PayloadByte::OsVersionMinor => host.os_version_minor,
+                PayloadByte::ProfileId => host.profile_id,
+            })
+            .collect()
+    }
+}
+impl TryFrom<Vec<String>> for PayloadTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(entries: Vec<String>) -> Result<Self, Self::Error> {
+        entries
+            .iter()
+            .map(|entry| entry.parse())
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadByte {
+    Literal(u8),
+    OsCode,
+    OsVersionMajor,
+    OsVersionMinor,
+    ProfileId,
+}
+impl std::str::FromStr for PayloadByte {
+    type Err = anyhow::Error;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        let entry = entry.trim();
+        if let Some(placeholder) = entry
+            .strip_prefix('{')
+            .and_then(|entry| entry.strip_suffix('}'))
+        {
+            return match placeholder {
+                "os_code" => Ok(Self::OsCode),
+                "os_version_major" => Ok(Self::OsVersionMajor),
+                "os_version_minor" => Ok(Self::OsVersionMinor),
+                "profile_id" => Ok(Self::ProfileId),
+                _ => anyhow::bail!("Unknown payload placeholder '{entry}'"),
+            };
+        }
+        let invalid = || format!("Invalid payload byte '{entry}'");
+        match entry
+            .strip_prefix("0x")
+            .or_else(|| entry.strip_prefix("0X"))
+        {
+            Some(digits) => u8::from_str_radix(digits, 16).with_context(invalid),
+            None => entry.parse().with_context(invalid),
+        }
+        .map(Self::Literal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::OsVariant;
+
+    fn template(entries: &[&str]) -> anyhow::Result<PayloadTemplate> {
+        entries
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<_>>()
+            .try_into()
+    }
+
+    #[test]
+    fn renders_literals_and_placeholders() {
+        let host = HostInfo {
+            os: OsVariant::Linux,
+            os_version_major: 24,
+            os_version_minor: 4,
+            distro_id: "ubuntu".to_owned(),
+            desktop: "GNOME".to_owned(),
+            hostname: "work".to_owned(),
+            profile_id: 2,
+        };
+        assert_eq!(
+            template(&[
+                "0x2A",
+                "{os_code}",
+                "{os_version_major}",
+                "{os_version_minor}",
+                "{profile_id}",
+                "7"
+            ])
+            .unwrap()
+            .render(&host),
+            [0x2A, 1, 24, 4, 2, 7]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!(template(&["{os_name}"]).is_err());
+        assert!(template(&["0x100"]).is_err());
+        assert!(template(&["-1"]).is_err());
+    }
+}
@@ -0,0 +1,658 @@
+//! Wire format shared with the keyboard firmware
+//!
+//! Every message is split into one or more [`PACKET_SIZE`] byte packets,
+//! QMK's raw HID report size, each starting with a fixed header:
+//!
+//! | byte | meaning                            |
+//! |------|------------------------------------|
+//! | 0    | command ID                         |
+//! | 1    | [`PROTOCOL_VERSION`]               |
+//! | 2    | chunk index, starting at 0         |
+//! | 3    | chunk count                        |
+//! | 4    | payload length within this chunk   |
+//! | 5..  | payload, zero padded               |
+//!
+//! The HID report ID is not part of the packet, it's prepended by the transport.
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// Bumped on any incompatible change to the packet layout or message payloads
+pub const PROTOCOL_VERSION: u8 = 2;
+/// QMK raw HID reports are limited to 32 bytes
+pub const PACKET_SIZE: usize = 32;
+const HEADER_SIZE: usize = 5;
+/// Payload bytes carried by a single packet
+pub const CHUNK_PAYLOAD_SIZE: usize = PACKET_SIZE - HEADER_SIZE;
+/// Longest payload that fits the chunk count byte
+pub const MAX_PAYLOAD_SIZE: usize = CHUNK_PAYLOAD_SIZE * u8::MAX as usize;
+
+pub type Packet = [u8; PACKET_SIZE];
+
+/// Command IDs
+pub mod command {
+    /// Board -> host acknowledgement
+    pub const ACK: u8 = 0x06;
+    /// Host -> board host OS report, matching the original QMK "reporting host" command
+    pub const HOST_OS: u8 = 42;
+    /// Host -> board layer switch
+    pub const SET_LAYER: u8 = 0x10;
+    /// Host -> board local date and time
+    pub const TIME: u8 = 0x11;
+    /// Host -> board system stats
+    pub const STATS: u8 = 0x12;
+    /// Host -> board media player state
+    pub const NOW_PLAYING: u8 = 0x13;
+    /// Host -> board distro, desktop environment and hostname
+    pub const HOST_INFO: u8 = 0x14;
+    /// Host -> board active keyboard layout
+    pub const LAYOUT: u8 = 0x15;
+    /// Host -> board default audio output volume and mute state
+    pub const VOLUME: u8 = 0x16;
+    /// Host -> board screen lock and idle state
+    pub const SESSION_STATE: u8 = 0x17;
+    /// Board -> host request to run one of the keyboard's configured actions
+    pub const ACTION: u8 = 0x20;
+}
+
+/// Stats temperature byte when no thermal zone is available
+const UNKNOWN_TEMPERATURE: u8 = u8::MAX;
+/// Now playing texts are truncated so the whole message fits 3 packets
+pub const MAX_TITLE_LEN: usize = 48;
+pub const MAX_ARTIST_LEN: usize = 30;
+/// Host info texts are truncated so the whole message fits 3 packets
+pub const MAX_HOST_TEXT_LEN: usize = 24;
+/// Layout names are truncated so the message fits a single packet
+pub const MAX_LAYOUT_NAME_LEN: usize = CHUNK_PAYLOAD_SIZE - 2;
+
+/// [MPRIS playback status](https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html#Enum:Playback_Status)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackStatus {
+    Stopped = 0,
+    Playing = 1,
+    Paused = 2,
+}
+impl TryFrom<u8> for PlaybackStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> anyhow::Result<Self> {
+        Ok(match value {
+            0 => PlaybackStatus::Stopped,
+            1 => PlaybackStatus::Playing,
+            2 => PlaybackStatus::Paused,
+            _ => anyhow::bail!("Unknown playback status {value}"),
+        })
+    }
+}
+
+/// Serialised for the control socket clients, e.g. `{"type":"action","name":"lock"}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Host OS as a [QMK OS enum](https://github.com/qmk/qmk_firmware/blob/26f898c8a538b808cf506f558a9454f7f50e3ba6/quantum/os_detection.h#L23) value
+    /// and the ID of the host profile, 0 without one
+    HostOs { os_code: u8, profile_id: u8 },
+    /// Switch the board to the given layer
+    SetLayer { layer: u8 },
+    /// Host local date and time, `weekday` counts from Monday = 0
+    Time {
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+        weekday: u8,
+        utc_offset_minutes: i16,
+    },
+    /// Host system load, the temperature saturates below 255 °C
+    Stats {
+        cpu_percent: u8,
+        memory_percent: u8,
+        temperature_celsius: Option<u8>,
+    },
+    /// Media player state, build it with [`Message::now_playing`] to fit the text limits
+    NowPlaying {
+        status: PlaybackStatus,
+        title: String,
+        artist: String,
+    },
+    /// Host descriptors, build it with [`Message::host_info`] to fit the text limits
+    HostInfo {
+        distro_id: String,
+        desktop: String,
+        hostname: String,
+    },
+    /// Active keyboard layout (XKB group), build it with [`Message::layout`] to fit the text limit
+    Layout { index: u8, name: String },
+    /// Default audio output volume, saturating above 255 %
+    Volume { percent: u8, muted: bool },
+    /// Whether the host session is locked and whether the user is idle
+    SessionState { locked: bool, idle: bool },
+    /// Board requesting the host action configured under `name`
+    Action { name: String },
+    /// Board acknowledging a host message, `value` depends on the acknowledged command
+    Ack { command: u8, value: u8 },
+}
+impl Message {
+    /// Media player state with the texts truncated to [`MAX_TITLE_LEN`] and [`MAX_ARTIST_LEN`] bytes
+    pub fn now_playing(status: PlaybackStatus, title: &str, artist: &str) -> Self {
+        Message::NowPlaying {
+            status,
+            title: truncate(title, MAX_TITLE_LEN).to_owned(),
+            artist: truncate(artist, MAX_ARTIST_LEN).to_owned(),
+        }
+    }
+
+    /// Host descriptors with the texts truncated to [`MAX_HOST_TEXT_LEN`] bytes
+    pub fn host_info(distro_id: &str, desktop: &str, hostname: &str) -> Self {
+        Message::HostInfo {
+            distro_id: truncate(distro_id, MAX_HOST_TEXT_LEN).to_owned(),
+            desktop: truncate(desktop, MAX_HOST_TEXT_LEN).to_owned(),
+            hostname: truncate(hostname, MAX_HOST_TEXT_LEN).to_owned(),
+        }
+    }
+
+    /// Keyboard layout with the name truncated to [`MAX_LAYOUT_NAME_LEN`] bytes
+    pub fn layout(index: u8, name: &str) -> Self {
+        Message::Layout {
+            index,
+            name: truncate(name, MAX_LAYOUT_NAME_LEN).to_owned(),
+        }
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            Message::HostOs { .. } => command::HOST_OS,
+            Message::SetLayer { .. } => command::SET_LAYER,
+            Message::Time { .. } => command::TIME,
+            Message::Stats { .. } => command::STATS,
+            Message::NowPlaying { .. } => command::NOW_PLAYING,
+            Message::HostInfo { .. } => command::HOST_INFO,
+            Message::Layout { .. } => command::LAYOUT,
+            Message::Volume { .. } => command::VOLUME,
+            Message::SessionState { .. } => command::SESSION_STATE,
+            Message::Action { .. } => command::ACTION,
+            Message::Ack { .. } => command::ACK,
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            Message::HostOs {
+                os_code,
+                profile_id,
+            } => vec![*os_code, *profile_id],
+            Message::SetLayer { layer } => vec![*layer],
+            Message::Time {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                weekday,
+                utc_offset_minutes,
+            } => {
+                let mut payload = year.to_le_bytes().to_vec();
+                payload.extend_from_slice(&[*month, *day, *hour, *minute, *second, *weekday]);
+                payload.extend_from_slice(&utc_offset_minutes.to_le_bytes());
+                payload
+            }
+            Message::Stats {
+                cpu_percent,
+                memory_percent,
+                temperature_celsius,
+            } => vec![
+                *cpu_percent,
+                *memory_percent,
+                temperature_celsius.map_or(UNKNOWN_TEMPERATURE, |celsius| {
+                    celsius.min(UNKNOWN_TEMPERATURE - 1)
+                }),
+            ],
+            Message::NowPlaying {
+                status,
+                title,
+                artist,
+            } => {
+                let mut payload = vec![*status as u8];
+                push_text(&mut payload, title);
+                push_text(&mut payload, artist);
+                payload
+            }
+            Message::HostInfo {
+                distro_id,
+                desktop,
+                hostname,
+            } => {
+                let mut payload = Vec::new();
+                push_text(&mut payload, distro_id);
+                push_text(&mut payload, desktop);
+                push_text(&mut payload, hostname);
+                payload
+            }
+            Message::Layout { index, name } => {
+                let mut payload = vec![*index];
+                push_text(&mut payload, name);
+                payload
+            }
+            Message::Volume { percent, muted } => vec![*percent, u8::from(*muted)],
+            Message::SessionState { locked, idle } => vec![u8::from(*locked), u8::from(*idle)],
+            Message::Action { name } => {
+                let mut payload = Vec::new();
+                push_text(&mut payload, name);
+                payload
+            }
+            Message::Ack { command, value } => vec![*command, *value],
+        }
+    }
+
+    fn from_payload(command: u8, payload: &[u8]) -> anyhow::Result<Self> {
+        Ok(match command {
+            command::HOST_OS => {
+                let [os_code, profile_id] = fixed_payload(command, payload)?;
+                Message::HostOs {
+                    os_code,
+                    profile_id,
+                }
+            }
+            command::SET_LAYER => {
+                let [layer] = fixed_payload(command, payload)?;
+                Message::SetLayer { layer }
+            }
+            command::TIME => {
+                let [year_lo, year_hi, month, day, hour, minute, second, weekday, offset_lo, offset_hi] =
+                    fixed_payload(command, payload)?;
+                Message::Time {
+                    year: u16::from_le_bytes([year_lo, year_hi]),
+                    month,
+                    day,
+                    hour,
+                    minute,
+                    second,
+                    weekday,
+                    utc_offset_minutes: i16::from_le_bytes([offset_lo, offset_hi]),
+                }
+            }
+            command::STATS => {
+                let [cpu_percent, memory_percent, temperature] = fixed_payload(command, payload)?;
+                Message::Stats {
+                    cpu_percent,
+                    memory_percent,
+                    temperature_celsius: (temperature != UNKNOWN_TEMPERATURE)
+                        .then_some(temperature),
+                }
+            }
+            command::NOW_PLAYING => {
+                let (status, mut texts) = payload.split_first().context("Empty now playing")?;
+                Message::NowPlaying {
+                    status: PlaybackStatus::try_from(*status)?,
+                    title: read_text(&mut texts)?,
+                    artist: read_text(&mut texts)?,
+                }
+            }
+            command::HOST_INFO => {
+                let mut texts = payload;
+                Message::HostInfo {
+                    distro_id: read_text(&mut texts)?,
+                    desktop: read_text(&mut texts)?,
+                    hostname: read_text(&mut texts)?,
+                }
+            }
+            command::LAYOUT => {
+                let (index, mut texts) = payload.split_first().context("Empty layout")?;
+                Message::Layout {
+                    index: *index,
+                    name: read_text(&mut texts)?,
+                }
+            }
+            command::VOLUME => {
+                let [percent, muted] = fixed_payload(command, payload)?;
+                Message::Volume {
+                    percent,
+                    muted: muted != 0,
+                }
+            }
+            command::SESSION_STATE => {
+                let [locked, idle] = fixed_payload(command, payload)?;
+                Message::SessionState {
+                    locked: locked != 0,
+                    idle: idle != 0,
+                }
+            }
+            command::ACTION => {
+                let mut texts = payload;
+                Message::Action {
+                    name: read_text(&mut texts)?,
+                }
+            }
+            command::ACK => {
+                let [command, value] = fixed_payload(command, payload)?;
+                Message::Ack { command, value }
+            }
+            _ => anyhow::bail!("Unknown command {command:#04x}"),
+        })
+    }
+}
+
+fn fixed_payload<const N: usize>(command: u8, payload: &[u8]) -> anyhow::Result<[u8; N]> {
+    payload.try_into().context(format!(
+        "Command {command:#04x} expects a {N} byte payload, got {} bytes",
+        payload.len()
+    ))
+}
+
+/// Longest prefix of `text` that fits `max_len` bytes without splitting a character
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut len = text.len().min(max_len);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    &text[..len]
+}
+
+/// Append a length prefixed UTF-8 string, truncated to 255 bytes
+fn push_text(payload: &mut Vec<u8>, text: &str) {
+    let text = truncate(text, u8::MAX.into());
+    payload.push(text.len() as u8);
+    payload.extend_from_slice(text.as_bytes());
+}
+
+/// Read a string written by [`push_text`], advancing `payload` past it
+fn read_text(payload: &mut &[u8]) -> anyhow::Result<String> {
+    let (len, rest) = payload.split_first().context("Missing text length")?;
+    let (text, rest) = rest
+        .split_at_checked(*len as usize)
+        .context(format!("Text of {len} bytes exceeds the payload"))?;
+    *payload = rest;
+    Ok(String::from_utf8(text.to_vec())?)
+}
+
+/// Split a message into packets ready to be written to the board
+pub fn encode(message: &Message) -> anyhow::Result<Vec<Packet>> {
+    let payload = message.payload();
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        anyhow::bail!(
+            "Payload of {} bytes exceeds the {MAX_PAYLOAD_SIZE} byte limit",
+            payload.len()
+        );
+    }
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![&[]]
+    } else {
+        payload.chunks(CHUNK_PAYLOAD_SIZE).collect()
+    };
+    let count = chunks.len() as u8;
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut packet = [0; PACKET_SIZE];
+            packet[..HEADER_SIZE].copy_from_slice(&[
+                message.command(),
+                PROTOCOL_VERSION,
+                index as u8,
+                count,
+                chunk.len() as u8,
+            ]);
+            packet[HEADER_SIZE..HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+            packet
+        })
+        .collect())
+}
+
+/// Decode a complete message from all of its packets
+pub fn decode(packets: &[Packet]) -> anyhow::Result<Message> {
+    let mut decoder = Decoder::default();
+    for packet in packets {
+        if let Some(message) = decoder.push(packet)? {
+            return Ok(message);
+        }
+    }
+    anyhow::bail!("Incomplete message")
+}
+
+/// Reassembles messages from packets arriving one at a time
+#[derive(Debug, Default)]
+pub struct Decoder {
+    pending: Option<Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    command: u8,
+    count: u8,
+    next_index: u8,
+    payload: Vec<u8>,
+}
+
+impl Decoder {
+    /// Feed the next received packet, returns the message once its last chunk arrived
+    ///
+    /// A malformed or out of order packet drops any partially received message.
+    pub fn push(&mut self, packet: &[u8]) -> anyhow::Result<Option<Message>> {
+        let pending = self.pending.take();
+        let [command, version, index, count, len, ref data @ ..] = *packet else {
+            anyhow::bail!(
+                "Packet of {} bytes is shorter than its header",
+                packet.len()
+            );
+        };
+        if version != PROTOCOL_VERSION {
+            anyhow::bail!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}");
+        }
+        if count == 0 || index >= count {
+            anyhow::bail!("Invalid chunk {index} of {count}");
+        }
+        let chunk = data
+            .get(..len as usize)
+            .filter(|chunk| chunk.len() <= CHUNK_PAYLOAD_SIZE)
+            .context(format!("Invalid chunk length {len}"))?;
+        let mut pending = match pending {
+            Some(pending) if index != 0 => pending,
+            _ => Pending {
+                command,
+                count,
+                next_index: 0,
+                payload: Vec::with_capacity(count as usize * CHUNK_PAYLOAD_SIZE),
+            },
+        };
+        if pending.command != command || pending.count != count || pending.next_index != index {
+            anyhow::bail!("Unexpected chunk {index} of {count} for command {command:#04x}");
+        }
+        pending.payload.extend_from_slice(chunk);
+        pending.next_index += 1;
+        if pending.next_index < pending.count {
+            self.pending = Some(pending);
+            return Ok(None);
+        }
+        Message::from_payload(pending.command, &pending.payload).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        let packets = encode(&message).unwrap();
+        assert_eq!(decode(&packets).unwrap(), message);
+    }
+
+    #[test]
+    fn host_os_round_trip() {
+        round_trip(Message::HostOs {
+            os_code: 1,
+            profile_id: 3,
+        });
+    }
+
+    #[test]
+    fn set_layer_round_trip() {
+        round_trip(Message::SetLayer { layer: 4 });
+    }
+
+    #[test]
+    fn time_round_trip() {
+        round_trip(Message::Time {
+            year: 2025,
+            month: 1,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 58,
+            weekday: 4,
+            utc_offset_minutes: -210,
+        });
+    }
+
+    #[test]
+    fn stats_round_trip() {
+        round_trip(Message::Stats {
+            cpu_percent: 12,
+            memory_percent: 100,
+            temperature_celsius: Some(54),
+        });
+        round_trip(Message::Stats {
+            cpu_percent: 0,
+            memory_percent: 40,
+            temperature_celsius: None,
+        });
+    }
+
+    #[test]
+    fn now_playing_round_trip() {
+        round_trip(Message::now_playing(
+            PlaybackStatus::Playing,
+            "Paranoid Android",
+            "Radiohead",
+        ));
+        round_trip(Message::now_playing(PlaybackStatus::Stopped, "", ""));
+    }
+
+    #[test]
+    fn host_info_round_trip() {
+        round_trip(Message::host_info("fedora", "GNOME", "work-laptop"));
+        round_trip(Message::host_info("", "", ""));
+    }
+
+    #[test]
+    fn host_info_fits_three_packets() {
+        let long = "x".repeat(MAX_HOST_TEXT_LEN * 2);
+        assert_eq!(
+            encode(&Message::host_info(&long, &long, &long))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn now_playing_fits_three_packets() {
+        let message = Message::now_playing(
+            PlaybackStatus::Paused,
+            &"ž".repeat(MAX_TITLE_LEN),
+            &"a".repeat(MAX_ARTIST_LEN * 2),
+        );
+        let Message::NowPlaying { title, artist, .. } = &message else {
+            unreachable!();
+        };
+        // the 2 byte characters can't be split in half
+        assert_eq!(title.len(), MAX_TITLE_LEN);
+        assert_eq!(artist.len(), MAX_ARTIST_LEN);
+        let packets = encode(&message).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(decode(&packets).unwrap(), message);
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        assert_eq!(truncate("až", 2), "a");
+        assert_eq!(truncate("až", 3), "až");
+    }
+
+    #[test]
+    fn layout_fits_one_packet() {
+        round_trip(Message::layout(1, "Czech (QWERTY)"));
+        let long = Message::layout(3, &"x".repeat(CHUNK_PAYLOAD_SIZE));
+        assert_eq!(encode(&long).unwrap().len(), 1);
+        assert_eq!(decode(&encode(&long).unwrap()).unwrap(), long);
+    }
+
+    #[test]
+    fn volume_round_trip() {
+        round_trip(Message::Volume {
+            percent: 150,
+            muted: true,
+        });
+    }
+
+    #[test]
+    fn session_state_round_trip() {
+        round_trip(Message::SessionState {
+            locked: true,
+            idle: false,
+        });
+    }
+
+    #[test]
+    fn action_round_trip() {
+        round_trip(Message::Action {
+            name: "lock_screen".to_owned(),
+        });
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        round_trip(Message::Ack {
+            command: command::HOST_OS,
+            value: 3,
+        });
+    }
+
+    #[test]
+    fn host_os_layout() {
+        let packets = encode(&Message::HostOs {
+            os_code: 2,
+            profile_id: 5,
+        })
+        .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0][..7],
+            [command::HOST_OS, PROTOCOL_VERSION, 0, 1, 2, 2, 5]
+        );
+        assert!(packets[0][7..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        let mut packets = encode(&Message::HostOs {
+            os_code: 1,
+            profile_id: 0,
+        })
+        .unwrap();
+        packets[0][1] = PROTOCOL_VERSION + 1;
+        assert!(decode(&packets).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        let mut packets = encode(&Message::HostOs {
+            os_code: 1,
+            profile_id: 0,
+        })
+        .unwrap();
+        packets[0][0] = 0xFF;
+        assert!(decode(&packets).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_order_chunks() {
+        let mut first = [0; PACKET_SIZE];
+        first[..HEADER_SIZE].copy_from_slice(&[command::ACK, PROTOCOL_VERSION, 1, 2, 0]);
+        let mut decoder = Decoder::default();
+        assert!(decoder.push(&first).is_err());
+    }
+}
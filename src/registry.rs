@@ -0,0 +1,142 @@
+//! Keyboards currently connected to the daemon
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// USB bus and address, the key of a plugged in device until it leaves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsbLocation {
+    pub bus: u8,
+    pub address: u8,
+}
+
+/// A configured keyboard on one USB device, timestamps are Unix seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardState {
+    pub keeb: String,
+    pub usb: UsbLocation,
+    /// Raw HID interfaces matching the keyboard when it was probed
+    pub paths: Vec<String>,
+    pub arrived_at: u64,
+    pub last_message: Option<SentMessage>,
+    /// Last layer switched to since the arrival, restored when the host resumes
+    pub layer: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub command: u8,
+    pub sent_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    boards: Vec<BoardState>,
+}
+impl Registry {
+    pub fn boards(&self) -> &[BoardState] {
+        &self.boards
+    }
+
+    pub fn is_connected(&self, keeb: &str) -> bool {
+        self.boards.iter().any(|board| board.keeb == keeb)
+    }
+
+    /// Record a successfully probed keyboard, replacing a previous record of the same device
+    pub fn arrived(&mut self, keeb: &str, usb: UsbLocation, paths: Vec<String>) {
+        self.boards
+            .retain(|board| board.keeb != keeb || board.usb != usb);
+        self.boards.push(BoardState {
+            keeb: keeb.to_owned(),
+            usb,
+            paths,
+            arrived_at: unix_now(),
+            last_message: None,
+            layer: None,
+        });
+    }
+
+    /// Forget the keyboards on the unplugged device, returns their last state
+    pub fn left(&mut self, usb: UsbLocation) -> Vec<BoardState> {
+        let (left, connected) = self.boards.drain(..).partition(|board| board.usb == usb);
+        self.boards = connected;
+        left
+    }
+
+    /// Forget the keyboards that are no longer configured
+    pub fn retain(&mut self, configured: impl Fn(&str) -> bool) {
+        self.boards.retain(|board| configured(&board.keeb));
+    }
+
+    /// Last layer switched to on any device of the keyboard
+    pub fn layer(&self, keeb: &str) -> Option<u8> {
+        self.boards
+            .iter()
+            .filter(|board| board.keeb == keeb)
+            .find_map(|board| board.layer)
+    }
+
+    pub fn layer_sent(&mut self, keeb: &str, layer: u8) {
+        for board in self.boards.iter_mut().filter(|board| board.keeb == keeb) {
+            board.layer = Some(layer);
+        }
+    }
+
+    pub fn message_sent(&mut self, keeb: &str, command: u8) {
+        let sent = SentMessage {
+            command,
+            sent_at: unix_now(),
+        };
+        for board in self.boards.iter_mut().filter(|board| board.keeb == keeb) {
+            board.last_message = Some(sent);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USB: UsbLocation = UsbLocation { bus: 1, address: 7 };
+
+    #[test]
+    fn departure_forgets_the_device() {
+        let mut registry = Registry::default();
+        registry.arrived("klor", USB, vec!["/dev/hidraw3".to_owned()]);
+        registry.arrived("corne", UsbLocation { bus: 1, address: 8 }, Vec::new());
+        registry.message_sent("klor", 0x10);
+        assert_eq!(registry.boards()[0].last_message.unwrap().command, 0x10);
+        assert_eq!(registry.left(USB)[0].paths, ["/dev/hidraw3"]);
+        assert!(!registry.is_connected("klor"));
+        assert!(registry.is_connected("corne"));
+    }
+
+    #[test]
+    fn arrival_replaces_the_same_device() {
+        let mut registry = Registry::default();
+        registry.arrived("klor", USB, Vec::new());
+        registry.arrived("klor", USB, Vec::new());
+        assert_eq!(registry.boards().len(), 1);
+        registry.retain(|keeb| keeb != "klor");
+        assert!(registry.boards().is_empty());
+    }
+
+    #[test]
+    fn remembers_the_layer_until_arrival() {
+        let mut registry = Registry::default();
+        registry.layer_sent("klor", 2);
+        assert_eq!(registry.layer("klor"), None);
+        registry.arrived("klor", USB, Vec::new());
+        registry.layer_sent("klor", 3);
+        assert_eq!(registry.layer("klor"), Some(3));
+        registry.arrived("klor", USB, Vec::new());
+        assert_eq!(registry.layer("klor"), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Long-lived raw HID sessions, keeping the interface open for both directions
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    config::KeyboardConfig,
+    events::{BoardEvent, EventBus},
+    protocol::{Decoder, Message},
+    transport::HidHandle,
+};
+
+/// How long the reader blocks the device per read, writes wait at most this long
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// An opened raw HID interface of a keyboard
+///
+/// A reader thread decodes the incoming reports, queueing the acks for the writer
+/// and publishing everything else on the event bus, while writes are serialised
+/// through the same lock. Dropping the session stops the reader and closes the interface.
+pub struct Session<D> {
+    keeb: String,
+    path: String,
+    device: Arc<Mutex<D>>,
+    /// Writes waiting for the device, the reader steps aside for them as the lock isn't fair
+    pending_writes: Arc<AtomicUsize>,
+    acks: Receiver<Message>,
+    /// Set when the reader stopped, either on a read error or when the session is dropped
+    closed: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+impl<D: HidHandle> Session<D> {
+    pub fn open(
+        keeb: &str,
+        path: String,
+        device: D,
+        keeb_config: &KeyboardConfig,
+        events: EventBus,
+    ) -> Self {
+        let device = Arc::new(Mutex::new(device));
+        let closed = Arc::new(AtomicBool::new(false));
+        let pending_writes = Arc::new(AtomicUsize::new(0));
+        let (ack_sender, acks) = mpsc::channel();
+        let reader = {
+            let keeb = keeb.to_owned();
+            let device = device.clone();
+            let closed = closed.clone();
+            let pending_writes = pending_writes.clone();
+            let report_len = keeb_config.report_size + 1;
+            // numbered input reports start with their report ID
+            let skip = usize::from(keeb_config.report_id != 0);
+            thread::spawn(move || {
+                let reader = Reader {
+                    keeb: &keeb,
+                    device: &device,
+                    closed: &closed,
+                    pending_writes: &pending_writes,
+                    acks: &ack_sender,
+                    events: &events,
+                };
+                if let Err(err) = reader.run(report_len, skip) {
+                    tracing::debug!(keeb, "Session reader stopped: {err:#}");
+                }
+                closed.store(true, Ordering::Relaxed);
+            })
+        };
+        tracing::debug!(keeb, path, "Session opened");
+        Self {
+            keeb: keeb.to_owned(),
+            path,
+            device,
+            pending_writes,
+            acks,
+            closed,
+            reader: Some(reader),
+        }
+    }
+
+    pub fn keeb(&self) -> &str {
+        &self.keeb
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether the interface failed, a closed session should be dropped and reopened
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Write a single report, the first byte being the report ID
+    pub fn write(&self, report: &[u8]) -> anyhow::Result<()> {
+        self.pending_writes.fetch_add(1, Ordering::Relaxed);
+        let result = self
+            .device
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write(report);
+        self.pending_writes.fetch_sub(1, Ordering::Relaxed);
+        result?;
+        Ok(())
+    }
+
+    /// Next ack sent by the board, `None` when none arrived within `timeout`
+    pub fn recv_ack(&self, timeout: Duration) -> Option<Message> {
+        self.acks.recv_timeout(timeout).ok()
+    }
+}
+impl<D> Drop for Session<D> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        tracing::debug!(keeb = self.keeb, path = self.path, "Session closed");
+    }
+}
+
+struct Reader<'a, D> {
+    keeb: &'a str,
+    device: &'a Mutex<D>,
+    closed: &'a AtomicBool,
+    pending_writes: &'a AtomicUsize,
+    acks: &'a Sender<Message>,
+    events: &'a EventBus,
+}
+impl<D: HidHandle> Reader<'_, D> {
+    fn run(&self, report_len: usize, skip: usize) -> anyhow::Result<()> {
+        let mut decoder = Decoder::default();
+        let mut buf = vec![0; report_len];
+        while !self.closed.load(Ordering::Relaxed) {
+            if self.pending_writes.load(Ordering::Relaxed) > 0 {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            let len = self
+                .device
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .read_timeout(&mut buf, READ_TIMEOUT)?;
+            if len <= skip {
+                continue;
+            }
+            // unrelated reports (e.g. VIA traffic) don't decode and are skipped
+            let Ok(Some(message)) = decoder.push(&buf[skip..len]) else {
+                continue;
+            };
+            if matches!(message, Message::Ack { .. }) {
+                if self.acks.send(message).is_err() {
+                    break;
+                }
+            } else {
+                self.events.publish(BoardEvent::Message {
+                    keeb: self.keeb.to_owned(),
+                    message,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{self, command},
+        transport::{DeviceInfo, HidTransport, MockTransport, MOCK_KEYBOARD},
+        Config,
+    };
+
+    #[test]
+    fn reads_board_messages_while_writing() {
+        let transport = MockTransport::new();
+        let device = DeviceInfo::mock("mock-0");
+        transport.attach(device.clone());
+        let mut config: Config = toml::from_str(MOCK_KEYBOARD).unwrap();
+        let keeb_config = config.keyboards.remove("klor").unwrap();
+        let events = EventBus::default();
+        let board_events = events.subscribe();
+        let session = Session::open(
+            "klor",
+            "mock-0".to_owned(),
+            transport.open(&device).unwrap(),
+            &keeb_config,
+            events,
+        );
+        let action = Message::Action {
+            name: "lock".to_owned(),
+        };
+        let ack = Message::Ack {
+            command: command::HOST_OS,
+            value: 1,
+        };
+        for message in [&action, &ack] {
+            for packet in protocol::encode(message).unwrap() {
+                transport.push_input(&device.path, &packet);
+            }
+        }
+        session.write(&[0, 1, 2]).unwrap();
+        assert_eq!(session.recv_ack(Duration::from_secs(1)), Some(ack));
+        assert_eq!(
+            board_events.recv_timeout(Duration::from_secs(1)),
+            Ok(BoardEvent::Message {
+                keeb: "klor".to_owned(),
+                message: action,
+            })
+        );
+        assert_eq!(transport.written()[0].data, [0, 1, 2]);
+        assert!(!session.is_closed());
+    }
+}
@@ -0,0 +1,411 @@
+//! Forward the screen lock and idle state of the user's graphical session
+//!
+//! The lock state comes from logind (`LockedHint` and the `Lock` / `Unlock` requests)
+//! and from the screen savers on the session bus, the idle state from logind's `IdleHint`.
+
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use anyhow::Context;
+use zbus::{
+    blocking::{Connection, MessageIterator},
+    message::Type,
+    proxy::CacheProperties,
+    zvariant::OwnedObjectPath,
+    MatchRule,
+};
+
+use crate::{
+    connection::SharedConnection, forward::StateForwarder, protocol::Message,
+    transport::HidTransport,
+};
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1",
+    gen_async = false
+)]
+trait Manager {
+    fn get_user(&self, uid: u32) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1",
+    gen_async = false
+)]
+trait User {
+    /// The user's graphical session as (ID, object path)
+    #[zbus(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1",
+    gen_async = false
+)]
+trait Session {
+    #[zbus(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn idle_hint(&self) -> zbus::Result<bool>;
+}
+
+#[derive(Debug)]
+enum Change {
+    /// logind's `Lock` / `Unlock` requests, many lockers act on them without setting `LockedHint`
+    LockRequested(bool),
+    LockedHint(bool),
+    Idle(bool),
+    ScreenSaver(bool),
+}
+
+/// Block the current thread, calling `on_change` with the current state
+/// and then whenever one of the sources reports a change
+pub fn watch(
+    system_bus: Connection,
+    session_bus: Connection,
+    on_change: &mut dyn FnMut(Message),
+) -> anyhow::Result<()> {
+    let (changes, received) = mpsc::channel();
+    {
+        let changes = changes.clone();
+        thread::spawn(move || {
+            if let Err(err) = watch_screen_saver(&session_bus, &changes) {
+                tracing::warn!("Screen saver watcher stopped: {err:#}");
+            }
+        });
+    }
+    thread::spawn(move || {
+        if let Err(err) = watch_logind(&system_bus, &changes) {
+            tracing::error!("logind watcher stopped: {err:#}");
+        }
+    });
+    let (mut lock_requested, mut locked_hint, mut idle, mut screen_saver) =
+        (false, false, false, false);
+    for change in received {
+        tracing::trace!(?change, "Session state change");
+        match change {
+            Change::LockRequested(value) => lock_requested = value,
+            Change::LockedHint(value) => locked_hint = value,
+            Change::Idle(value) => idle = value,
+            Change::ScreenSaver(value) => screen_saver = value,
+        }
+        on_change(Message::SessionState {
+            locked: lock_requested || locked_hint || screen_saver,
+            idle,
+        });
+    }
+    anyhow::bail!("Session state watchers stopped")
+}
+
+fn watch_logind(bus: &Connection, changes: &Sender<Change>) -> anyhow::Result<()> {
+    // the daemon usually runs as a user service outside of the graphical session
+    let uid = fs::metadata("/proc/self")?.uid();
+    let user = UserProxy::builder(bus)
+        .path(ManagerProxy::new(bus)?.get_user(uid)?)?
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    // the service may start before the user logs in to the desktop
+    let mut new_sessions = MessageIterator::for_match_rule(
+        MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface("org.freedesktop.login1.Manager")?
+            .member("SessionNew")?
+            .build(),
+        bus,
+        None,
+    )?;
+    let path = loop {
+        let (session_id, path) = user.display()?;
+        if !session_id.is_empty() {
+            break path;
+        }
+        tracing::info!(uid, "No graphical session yet, waiting for one");
+        new_sessions.next().context("D-Bus connection closed")??;
+    };
+    drop(new_sessions);
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .path(path.clone())?
+        .build();
+    let signals = MessageIterator::for_match_rule(rule, bus, None)?;
+    let session = SessionProxy::builder(bus)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    let send_hints = || -> anyhow::Result<()> {
+        changes.send(Change::LockedHint(session.locked_hint()?))?;
+        changes.send(Change::Idle(session.idle_hint()?))?;
+        Ok(())
+    };
+    send_hints()?;
+    for signal in signals {
+        let signal = signal?;
+        match signal.header().member().map(|member| member.as_str()) {
+            Some("Lock") => changes.send(Change::LockRequested(true))?,
+            Some("Unlock") => changes.send(Change::LockRequested(false))?,
+            Some("PropertiesChanged") => send_hints()?,
+            _ => {}
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+/// `ActiveChanged` of `org.freedesktop.ScreenSaver` (KDE, Xfce, ...) and `org.gnome.ScreenSaver`
+fn watch_screen_saver(bus: &Connection, changes: &Sender<Change>) -> anyhow::Result<()> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .member("ActiveChanged")?
+        .build();
+    for signal in MessageIterator::for_match_rule(rule, bus, None)? {
+        let signal = signal?;
+        let interface = signal
+            .header()
+            .interface()
+            .map(|interface| interface.to_string());
+        if !matches!(
+            interface.as_deref(),
+            Some("org.freedesktop.ScreenSaver" | "org.gnome.ScreenSaver")
+        ) {
+            continue;
+        }
+        changes.send(Change::ScreenSaver(signal.body().deserialize()?))?;
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+/// Watch the session and send its state to the keyboards with `session_state` enabled
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let forwarder = StateForwarder::new(connection, |keeb_config| keeb_config.session_state);
+        let result = Connection::system()
+            .and_then(|system_bus| Ok((system_bus, Connection::session()?)))
+            .map_err(anyhow::Error::from)
+            .and_then(|(system_bus, session_bus)| {
+                watch(system_bus, session_bus, &mut |state| forwarder.send(state))
+            });
+        if let Err(err) = result {
+            tracing::error!("Session state watcher stopped: {err:#}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::Receiver,
+        time::{Duration, Instant},
+    };
+
+    use zbus::{
+        blocking::object_server::InterfaceRef, names::BusName, object_server::SignalEmitter,
+        zvariant::ObjectPath,
+    };
+
+    use super::*;
+    use crate::test_bus::PrivateBus;
+
+    const USER_PATH: &str = "/org/freedesktop/login1/user/_1000";
+    const SESSION_PATH: &str = "/org/freedesktop/login1/session/_32";
+
+    struct FakeManager;
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeManager {
+        fn get_user(&self, _uid: u32) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(USER_PATH).unwrap()
+        }
+
+        #[zbus(signal)]
+        async fn session_new(
+            emitter: &SignalEmitter<'_>,
+            id: &str,
+            path: ObjectPath<'_>,
+        ) -> zbus::Result<()>;
+    }
+
+    struct FakeUser {
+        session_id: String,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.User")]
+    impl FakeUser {
+        #[zbus(property)]
+        fn display(&self) -> (String, OwnedObjectPath) {
+            let path = if self.session_id.is_empty() {
+                "/"
+            } else {
+                SESSION_PATH
+            };
+            (
+                self.session_id.clone(),
+                OwnedObjectPath::try_from(path).unwrap(),
+            )
+        }
+    }
+
+    struct FakeSession {
+        idle: bool,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        #[zbus(property)]
+        fn locked_hint(&self) -> bool {
+            false
+        }
+
+        #[zbus(property)]
+        fn idle_hint(&self) -> bool {
+            self.idle
+        }
+
+        /// What `loginctl lock-session` makes logind emit
+        #[zbus(signal)]
+        async fn lock(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+    }
+
+    /// A connection owning the logind name, serving a user with or without a graphical session
+    fn fake_logind(bus: &PrivateBus, session_id: &str) -> Connection {
+        let logind = bus.connect();
+        {
+            let object_server = logind.object_server();
+            object_server
+                .at("/org/freedesktop/login1", FakeManager)
+                .unwrap();
+            object_server
+                .at(
+                    USER_PATH,
+                    FakeUser {
+                        session_id: session_id.to_owned(),
+                    },
+                )
+                .unwrap();
+            object_server
+                .at(SESSION_PATH, FakeSession { idle: false })
+                .unwrap();
+        }
+        logind.request_name("org.freedesktop.login1").unwrap();
+        logind
+    }
+
+    fn watch_in_background(bus: &PrivateBus) -> Receiver<Message> {
+        let (states, received) = mpsc::channel();
+        let (system_bus, session_bus) = (bus.connect(), bus.connect());
+        thread::spawn(move || {
+            watch(system_bus, session_bus, &mut |state| {
+                let _ = states.send(state);
+            })
+        });
+        received
+    }
+
+    fn state(locked: bool, idle: bool) -> Message {
+        Message::SessionState { locked, idle }
+    }
+
+    /// Skip the intermediate states until `expected` arrives
+    fn expect(states: &Receiver<Message>, expected: Message) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if states.recv_timeout(Duration::from_millis(100)).ok() == Some(expected.clone()) {
+                return;
+            }
+        }
+        panic!("No {expected:?}");
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn follows_logind_and_the_screen_saver() {
+        let bus = PrivateBus::start();
+        let logind = fake_logind(&bus, "32");
+        let states = watch_in_background(&bus);
+        expect(&states, state(false, false));
+
+        let session: InterfaceRef<FakeSession> =
+            logind.object_server().interface(SESSION_PATH).unwrap();
+        session.get_mut().idle = true;
+        zbus::block_on(session.get().idle_hint_changed(session.signal_emitter())).unwrap();
+        expect(&states, state(false, true));
+
+        // the screen saver watcher subscribes in its own thread, so repeat until it's listening
+        let screen_saver = bus.connect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            screen_saver
+                .emit_signal(
+                    None::<BusName>,
+                    "/org/freedesktop/ScreenSaver",
+                    "org.freedesktop.ScreenSaver",
+                    "ActiveChanged",
+                    &(true,),
+                )
+                .unwrap();
+            if states.recv_timeout(Duration::from_millis(100)).ok() == Some(state(true, true)) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "Screen saver ignored");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn lock_request_outlasts_hint_changes() {
+        let bus = PrivateBus::start();
+        let logind = fake_logind(&bus, "32");
+        let states = watch_in_background(&bus);
+        expect(&states, state(false, false));
+
+        // a locker like swaylock started on `Lock` leaves `LockedHint` unset
+        let session: InterfaceRef<FakeSession> =
+            logind.object_server().interface(SESSION_PATH).unwrap();
+        zbus::block_on(FakeSession::lock(session.signal_emitter())).unwrap();
+        expect(&states, state(true, false));
+
+        session.get_mut().idle = true;
+        zbus::block_on(session.get().idle_hint_changed(session.signal_emitter())).unwrap();
+        loop {
+            let Message::SessionState { locked, idle } =
+                states.recv_timeout(Duration::from_secs(5)).unwrap()
+            else {
+                unreachable!("Only session states are sent");
+            };
+            assert!(locked, "Unlocked by the idle change");
+            if idle {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn waits_for_a_graphical_session() {
+        let bus = PrivateBus::start();
+        let logind = fake_logind(&bus, "");
+        let states = watch_in_background(&bus);
+        assert!(states.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let user: InterfaceRef<FakeUser> = logind.object_server().interface(USER_PATH).unwrap();
+        user.get_mut().session_id = "32".to_owned();
+        let manager: InterfaceRef<FakeManager> = logind
+            .object_server()
+            .interface("/org/freedesktop/login1")
+            .unwrap();
+        zbus::block_on(FakeManager::session_new(
+            manager.signal_emitter(),
+            "32",
+            ObjectPath::try_from(SESSION_PATH).unwrap(),
+        ))
+        .unwrap();
+        expect(&states, state(false, false));
+    }
+}
@@ -0,0 +1,104 @@
+//! Probe the keyboards again when the host wakes up from suspend
+//!
+//! The layer is restored by the probe, the watched host states are sent again by their
+//! [`StateForwarder`](crate::forward::StateForwarder)s on the [`BoardEvent::Reprobed`](crate::events::BoardEvent::Reprobed) event.
+
+use std::{thread, time::Duration};
+
+use zbus::{
+    blocking::{Connection, MessageIterator},
+    message::Type,
+    MatchRule,
+};
+
+use crate::{connection::SharedConnection, transport::HidTransport};
+
+/// Time for the USB devices to wake up before they're probed
+const RESUME_DELAY: Duration = Duration::from_millis(500);
+
+/// Block the current thread, calling `on_resume` after every logind `PrepareForSleep(false)`
+pub fn watch(bus: &Connection, on_resume: &mut dyn FnMut()) -> anyhow::Result<()> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.login1.Manager")?
+        .member("PrepareForSleep")?
+        .path("/org/freedesktop/login1")?
+        .build();
+    for signal in MessageIterator::for_match_rule(rule, bus, None)? {
+        let going_to_sleep: bool = signal?.body().deserialize()?;
+        tracing::debug!(going_to_sleep, "PrepareForSleep");
+        if !going_to_sleep {
+            on_resume();
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
+
+/// Watch for system resumes and probe the connected keyboards again after each
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let result = Connection::system()
+            .map_err(anyhow::Error::from)
+            .and_then(|bus| {
+                watch(&bus, &mut || {
+                    tracing::info!("Resumed, probing the connected keyboards again");
+                    thread::sleep(RESUME_DELAY);
+                    connection.reprobe_connected();
+                })
+            });
+        if let Err(err) = result {
+            tracing::error!("Suspend watcher stopped: {err:#}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use zbus::names::BusName;
+
+    use super::*;
+    use crate::test_bus::PrivateBus;
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn resumes_after_prepare_for_sleep_false() {
+        let bus = PrivateBus::start();
+        let (resumes, resumed) = mpsc::channel();
+        {
+            let bus = bus.connect();
+            thread::spawn(move || {
+                watch(&bus, &mut || {
+                    let _ = resumes.send(());
+                })
+            });
+        }
+        let logind = bus.connect();
+        let prepare_for_sleep = |going_to_sleep: bool| {
+            logind
+                .emit_signal(
+                    None::<BusName>,
+                    "/org/freedesktop/login1",
+                    "org.freedesktop.login1.Manager",
+                    "PrepareForSleep",
+                    &(going_to_sleep,),
+                )
+                .unwrap();
+        };
+        // the watcher subscribes in its own thread, so repeat until it's listening
+        for _ in 0..50 {
+            prepare_for_sleep(true);
+            prepare_for_sleep(false);
+            if resumed.recv_timeout(Duration::from_millis(100)).is_ok() {
+                break;
+            }
+        }
+        // only the resumes count, not the suspends
+        while resumed.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        prepare_for_sleep(true);
+        assert!(resumed.recv_timeout(Duration::from_millis(200)).is_err());
+        prepare_for_sleep(false);
+        resumed.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
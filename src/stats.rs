@@ -0,0 +1,212 @@
+//! Stream CPU, memory and temperature stats to keyboard displays
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    config::StatsConfig, connection::SharedConnection, protocol::Message, transport::HidTransport,
+};
+
+/// Reads the stats from `/proc` and `/sys`, or from fixture directories mirroring their layout
+pub struct StatsSampler {
+    proc_root: PathBuf,
+    sys_root: PathBuf,
+    thermal_zone: Option<String>,
+    /// `(busy, total)` CPU jiffies of the previous sample
+    previous_cpu: Option<(u64, u64)>,
+    /// Whether the missing temperature was reported already
+    temperature_warned: bool,
+}
+impl StatsSampler {
+    pub fn new(config: &StatsConfig) -> Self {
+        Self::with_roots("/proc", "/sys", config.thermal_zone.clone())
+    }
+
+    pub fn with_roots(
+        proc_root: impl Into<PathBuf>,
+        sys_root: impl Into<PathBuf>,
+        thermal_zone: Option<String>,
+    ) -> Self {
+        Self {
+            proc_root: proc_root.into(),
+            sys_root: sys_root.into(),
+            thermal_zone,
+            previous_cpu: None,
+            temperature_warned: false,
+        }
+    }
+
+    /// CPU load is measured since the previous sample, or since boot for the first one
+    ///
+    /// An unreadable thermal zone only leaves out the temperature.
+    pub fn sample(&mut self) -> anyhow::Result<Message> {
+        let temperature_celsius = match self.temperature_celsius() {
+            Ok(temperature) => temperature,
+            Err(err) => {
+                if !self.temperature_warned {
+                    tracing::warn!("Temperature left out of the stats: {err:#}");
+                    self.temperature_warned = true;
+                }
+                None
+            }
+        };
+        Ok(Message::Stats {
+            cpu_percent: self.cpu_percent()?,
+            memory_percent: self.memory_percent()?,
+            temperature_celsius,
+        })
+    }
+
+    /// Switch the thermal zone, e.g. after a config reload
+    pub fn set_thermal_zone(&mut self, thermal_zone: Option<String>) {
+        if self.thermal_zone != thermal_zone {
+            self.thermal_zone = thermal_zone;
+            self.temperature_warned = false;
+        }
+    }
+
+    fn cpu_percent(&mut self) -> anyhow::Result<u8> {
+        let stat = read(&self.proc_root.join("stat"))?;
+        let jiffies = stat
+            .lines()
+            .find_map(|line| line.strip_prefix("cpu "))
+            .context("No aggregate cpu line in /proc/stat")?
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<u64>, _>>()?;
+        let total: u64 = jiffies.iter().sum();
+        // idle and iowait
+        let idle: u64 = jiffies.iter().skip(3).take(2).sum();
+        let busy = total - idle;
+        let (previous_busy, previous_total) =
+            self.previous_cpu.replace((busy, total)).unwrap_or_default();
+        Ok(percent(
+            busy.saturating_sub(previous_busy),
+            total.saturating_sub(previous_total),
+        ))
+    }
+
+    fn memory_percent(&self) -> anyhow::Result<u8> {
+        let meminfo = read(&self.proc_root.join("meminfo"))?;
+        let field = |name: &str| -> anyhow::Result<u64> {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|value| value.split_whitespace().next())
+                .context(format!("No {name} in /proc/meminfo"))?
+                .parse()
+                .context(format!("Invalid {name} in /proc/meminfo"))
+        };
+        let total = field("MemTotal")?;
+        Ok(percent(total.saturating_sub(field("MemAvailable")?), total))
+    }
+
+    fn temperature_celsius(&self) -> anyhow::Result<Option<u8>> {
+        let Some(zone) = &self.thermal_zone else {
+            return Ok(None);
+        };
+        let zones = self.sys_root.join("class/thermal");
+        let zone_dir = fs::read_dir(&zones)
+            .context(format!("Thermal zones: {zones:?}"))?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .find(|dir| {
+                dir.file_name().is_some_and(|name| name == zone.as_str())
+                    || read(&dir.join("type")).is_ok_and(|zone_type| zone_type.trim() == zone)
+            })
+            .context(format!("Thermal zone '{zone}' not found"))?;
+        let millidegrees: i64 = read(&zone_dir.join("temp"))?.trim().parse()?;
+        Ok(Some((millidegrees / 1000).clamp(0, u8::MAX.into()) as u8))
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<String> {
+    fs::read_to_string(path).context(format!("Stats source: {path:?}"))
+}
+
+fn percent(part: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 0;
+    }
+    (part.min(total) * 100 / total) as u8
+}
+
+/// Sample the stats at the configured interval and send them to the keyboards with `stats` enabled
+///
+/// The interval and thermal zone are read from the current config before every sample.
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let mut sampler = StatsSampler::new(&connection.lock().config().stats);
+        loop {
+            let interval = {
+                let connection = connection.lock();
+                let config = &connection.config().stats;
+                sampler.set_thermal_zone(config.thermal_zone.clone());
+                Duration::from_millis(config.interval_ms)
+            };
+            thread::sleep(interval);
+            match sampler.sample() {
+                Ok(stats) => connection
+                    .lock()
+                    .broadcast_where(|keeb_config| keeb_config.stats, &stats),
+                Err(err) => tracing::warn!("Failed to sample stats: {err:#}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_sampler(thermal_zone: Option<&str>) -> StatsSampler {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/stats");
+        StatsSampler::with_roots(
+            fixtures.join("proc"),
+            fixtures.join("sys"),
+            thermal_zone.map(str::to_owned),
+        )
+    }
+
+    #[test]
+    fn samples_fixture_files() {
+        assert_eq!(
+            fixture_sampler(Some("x86_pkg_temp")).sample().unwrap(),
+            Message::Stats {
+                cpu_percent: 25,
+                memory_percent: 75,
+                temperature_celsius: Some(48),
+            }
+        );
+    }
+
+    #[test]
+    fn thermal_zone_by_dir_name() {
+        let Message::Stats {
+            temperature_celsius,
+            ..
+        } = fixture_sampler(Some("thermal_zone0")).sample().unwrap()
+        else {
+            unreachable!();
+        };
+        assert_eq!(temperature_celsius, Some(31));
+    }
+
+    #[test]
+    fn missing_thermal_zone() {
+        assert_eq!(
+            fixture_sampler(Some("gpu")).sample().unwrap(),
+            Message::Stats {
+                cpu_percent: 25,
+                memory_percent: 75,
+                temperature_celsius: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Private D-Bus daemon for the tests of the D-Bus watchers and services
+//!
+//! The tests using it are `#[ignore]`d since they need `dbus-daemon` on the `PATH`,
+//! run them with `cargo test -- --ignored`.
+//!
+//! The watchers and services take their bus connections instead of connecting on their own,
+//! so the tests can point them at a private bus. The watchers subscribe to their signals
+//! before querying the initial state, so a change in between isn't lost.
+
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+};
+
+use zbus::blocking::{connection::Builder, Connection};
+
+/// A `dbus-daemon` killed when dropped, so a failing test doesn't leave it running
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    pub fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon on the PATH");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_owned(),
+        }
+    }
+
+    /// A new connection to the bus
+    pub fn connect(&self) -> Connection {
+        Builder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
@@ -0,0 +1,64 @@
+//! Keep the clocks of RTC-less keyboards in sync with the host
+
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, Local, Timelike};
+
+use crate::{connection::SharedConnection, protocol::Message, transport::HidTransport};
+
+/// How often the per-keyboard intervals are checked
+const TICK: Duration = Duration::from_secs(1);
+
+/// Host local time as a protocol message
+pub fn local_time() -> Message {
+    let now = Local::now();
+    Message::Time {
+        year: now.year().clamp(0, u16::MAX.into()) as u16,
+        month: now.month() as u8,
+        day: now.day() as u8,
+        hour: now.hour() as u8,
+        minute: now.minute() as u8,
+        second: now.second() as u8,
+        weekday: now.weekday().num_days_from_monday() as u8,
+        utc_offset_minutes: (now.offset().local_minus_utc() / 60) as i16,
+    }
+}
+
+/// Periodically send the local time to the probed keyboards with time sync enabled,
+/// each at its own configured interval
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let mut last_sent: HashMap<String, Instant> = HashMap::new();
+        loop {
+            thread::sleep(TICK);
+            let mut connection = connection.lock();
+            let due: Vec<String> = connection
+                .config()
+                .keyboards
+                .iter()
+                .filter(|(keeb, keeb_config)| {
+                    connection.registry().is_connected(keeb)
+                        && keeb_config.time_sync.as_ref().is_some_and(|time_sync| {
+                            last_sent.get(*keeb).is_none_or(|sent| {
+                                sent.elapsed() >= Duration::from_secs(time_sync.interval_secs)
+                            })
+                        })
+                })
+                .map(|(keeb, _)| keeb.clone())
+                .collect();
+            for keeb in due {
+                match connection.send(&keeb, &local_time()) {
+                    Ok(true) => {
+                        last_sent.insert(keeb, Instant::now());
+                    }
+                    Ok(false) => {}
+                    Err(err) => tracing::warn!(keeb, "Failed to sync time: {err:#}"),
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,309 @@
+//! HID transport abstraction
+//!
+//! [`BoardConnection`](crate::BoardConnection) only talks to keyboards through [`HidTransport`],
+//! so the probe logic can run against real hardware ([`HidApiTransport`])
+//! or against the in-memory [`MockTransport`].
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{CStr, CString},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::registry::UsbLocation;
+
+/// HID interface as reported by [`HidTransport::devices`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Platform specific path used to open the device
+    pub path: CString,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usage: u16,
+    pub usage_page: u16,
+    /// USB interface number, -1 when the platform doesn't report it
+    pub interface_number: i32,
+    /// USB device the interface belongs to, `None` when it couldn't be determined
+    pub usb: Option<UsbLocation>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// Source of HID devices
+pub trait HidTransport: Send {
+    type Device: HidHandle;
+
+    /// Enumerate the currently attached HID interfaces
+    fn devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>>;
+
+    /// Open the given HID interface for reading and writing
+    fn open(&self, device: &DeviceInfo) -> anyhow::Result<Self::Device>;
+}
+
+/// Opened HID interface
+pub trait HidHandle: Send + 'static {
+    /// Write a single report, the first byte being the report ID
+    fn write(&self, data: &[u8]) -> anyhow::Result<usize>;
+
+    /// Read a single input report into `buf`,
+    /// returns `Ok(0)` when nothing arrived within `timeout`
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize>;
+}
+
+/// [`HidTransport`] backed by the system HID API
+pub struct HidApiTransport {
+    hid_api: hidapi::HidApi,
+}
+impl HidApiTransport {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            hid_api: hidapi::HidApi::new()?,
+        })
+    }
+}
+impl HidTransport for HidApiTransport {
+    type Device = hidapi::HidDevice;
+
+    fn devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>> {
+        // the device list is cached by hidapi, so it has to be refreshed to see newly plugged boards
+        self.hid_api.refresh_devices()?;
+        Ok(self
+            .hid_api
+            .device_list()
+            .map(|device| DeviceInfo {
+                path: device.path().to_owned(),
+                vendor_id: device.vendor_id(),
+                product_id: device.product_id(),
+                usage: device.usage(),
+                usage_page: device.usage_page(),
+                interface_number: device.interface_number(),
+                usb: usb_location(Path::new("/sys"), device.path()),
+                serial_number: device.serial_number().map(str::to_owned),
+                manufacturer: device.manufacturer_string().map(str::to_owned),
+                product: device.product_string().map(str::to_owned),
+            })
+            .collect())
+    }
+
+    fn open(&self, device: &DeviceInfo) -> anyhow::Result<Self::Device> {
+        Ok(self.hid_api.open_path(&device.path)?)
+    }
+}
+/// USB device of a hidraw node like `/dev/hidraw3`, found by walking up from its sysfs device,
+/// the HID device under the USB interface, to the USB device carrying the bus and device numbers
+#[cfg(target_os = "linux")]
+fn usb_location(sys_root: &Path, path: &CStr) -> Option<UsbLocation> {
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+
+    let node = Path::new(OsStr::from_bytes(path.to_bytes())).file_name()?;
+    let sys_root = fs::canonicalize(sys_root).ok()?;
+    let device = fs::canonicalize(sys_root.join("class/hidraw").join(node).join("device")).ok()?;
+    device
+        .ancestors()
+        .take_while(|dir| dir.starts_with(&sys_root))
+        .find_map(|dir| {
+            let number = |file| fs::read_to_string(dir.join(file)).ok()?.trim().parse().ok();
+            Some(UsbLocation {
+                bus: number("busnum")?,
+                address: number("devnum")?,
+            })
+        })
+}
+
+/// hidapi's paths don't lead to the USB device outside of Linux
+#[cfg(not(target_os = "linux"))]
+fn usb_location(_sys_root: &Path, _path: &CStr) -> Option<UsbLocation> {
+    None
+}
+
+impl HidHandle for hidapi::HidDevice {
+    fn write(&self, data: &[u8]) -> anyhow::Result<usize> {
+        Ok(hidapi::HidDevice::write(self, data)?)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize> {
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        Ok(hidapi::HidDevice::read_timeout(self, buf, timeout)?)
+    }
+}
+
+/// Config of the keyboard behind [`DeviceInfo::mock`], more keys can be appended to it
+pub const MOCK_KEYBOARD: &str = "[keyboards.klor]\nvendor_id = 0x3a3c\nproduct_id = 0x0001\n";
+
+/// USB device of [`DeviceInfo::mock`]
+pub const MOCK_USB: UsbLocation = UsbLocation { bus: 1, address: 7 };
+
+impl DeviceInfo {
+    /// Raw HID interface of the [`MOCK_KEYBOARD`], to be attached to a [`MockTransport`]
+    pub fn mock(path: &str) -> Self {
+        Self {
+            path: CString::new(path).unwrap(),
+            vendor_id: 0x3a3c,
+            product_id: 0x0001,
+            usage: 0x61,
+            usage_page: 0xFF60,
+            interface_number: 1,
+            usb: Some(MOCK_USB),
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        }
+    }
+}
+
+/// Report written to a [`MockTransport`] device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrittenReport {
+    pub path: CString,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    devices: Vec<DeviceInfo>,
+    written: Vec<WrittenReport>,
+    input: HashMap<CString, VecDeque<Vec<u8>>>,
+    /// Paths whose opens fail, like a node udev hasn't granted access to
+    failing_opens: HashSet<CString>,
+    open_attempts: HashMap<CString, usize>,
+}
+
+/// In-memory [`HidTransport`] for running the probe flow without any USB hardware
+///
+/// Clones share the same state, so a clone kept by a test can inspect
+/// the reports written through the transport owned by a [`BoardConnection`](crate::BoardConnection).
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plug in a device, making it visible to enumeration
+    pub fn attach(&self, device: DeviceInfo) {
+        self.state().devices.push(device);
+    }
+
+    /// Unplug a device
+    pub fn detach(&self, path: &CStr) {
+        self.state()
+            .devices
+            .retain(|device| device.path.as_c_str() != path);
+    }
+
+    /// Queue an input report to be returned by the next read from the device at `path`
+    pub fn push_input(&self, path: &CStr, data: &[u8]) {
+        self.state()
+            .input
+            .entry(path.to_owned())
+            .or_default()
+            .push_back(data.to_vec());
+    }
+
+    /// Make every following open of the device at `path` fail
+    pub fn fail_opens(&self, path: &CStr) {
+        self.state().failing_opens.insert(path.to_owned());
+    }
+
+    /// How often the device at `path` was opened, including the failed attempts
+    pub fn open_attempts(&self, path: &CStr) -> usize {
+        self.state()
+            .open_attempts
+            .get(path)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// All reports written so far, oldest first
+    pub fn written(&self) -> Vec<WrittenReport> {
+        self.state().written.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+impl HidTransport for MockTransport {
+    type Device = MockDevice;
+
+    fn devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>> {
+        Ok(self.state().devices.clone())
+    }
+
+    fn open(&self, device: &DeviceInfo) -> anyhow::Result<Self::Device> {
+        let mut state = self.state();
+        *state.open_attempts.entry(device.path.clone()).or_default() += 1;
+        if state.failing_opens.contains(&device.path) {
+            anyhow::bail!("Mock device {:?} can't be opened", device.path);
+        }
+        state
+            .devices
+            .iter()
+            .find(|attached| attached.path == device.path)
+            .context(format!("Mock device {:?} not attached", device.path))?;
+        Ok(MockDevice {
+            path: device.path.clone(),
+            transport: self.clone(),
+        })
+    }
+}
+
+/// Device opened through a [`MockTransport`]
+#[derive(Debug)]
+pub struct MockDevice {
+    path: CString,
+    transport: MockTransport,
+}
+impl HidHandle for MockDevice {
+    fn write(&self, data: &[u8]) -> anyhow::Result<usize> {
+        let mut state = self.transport.state();
+        if !state.devices.iter().any(|device| device.path == self.path) {
+            anyhow::bail!("Mock device {:?} detached", self.path);
+        }
+        state.written.push(WrittenReport {
+            path: self.path.clone(),
+            data: data.to_vec(),
+        });
+        Ok(data.len())
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize> {
+        let report = self
+            .transport
+            .state()
+            .input
+            .get_mut(&self.path)
+            .and_then(VecDeque::pop_front);
+        let Some(report) = report else {
+            // behave like a real device that stays silent
+            thread::sleep(timeout);
+            return Ok(0);
+        };
+        let len = report.len().min(buf.len());
+        buf[..len].copy_from_slice(&report[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_usb_device_of_a_hidraw_node() {
+        let sys_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sysfs");
+        assert_eq!(
+            usb_location(&sys_root, c"/dev/hidraw3"),
+            Some(UsbLocation { bus: 1, address: 7 })
+        );
+        assert_eq!(usb_location(&sys_root, c"/dev/hidraw4"), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Forward the volume and mute state of the default PulseAudio / PipeWire output
+//!
+//! Goes through `pactl`, which talks to both PulseAudio and pipewire-pulse.
+
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    thread,
+};
+
+use anyhow::Context;
+
+use crate::{
+    connection::SharedConnection, forward::StateForwarder, protocol::Message,
+    transport::HidTransport,
+};
+
+const DEFAULT_SINK: &str = "@DEFAULT_SINK@";
+
+/// `pactl subscribe` child, killed on every way out of [`watch`]
+struct Subscription(Child);
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Block the current thread, calling `on_change` with the current volume
+/// and then whenever a sink or the default sink changes
+///
+/// A failing query is logged and skipped, e.g. while the default sink is being switched.
+pub fn watch(on_change: &mut dyn FnMut(Message)) -> anyhow::Result<()> {
+    let mut subscription = Subscription(
+        pactl_command()
+            .arg("subscribe")
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to start pactl")?,
+    );
+    let mut update = || match volume() {
+        Ok(volume) => on_change(volume),
+        Err(err) => tracing::warn!("Failed to query the volume: {err:#}"),
+    };
+    update();
+    let lines = BufReader::new(subscription.0.stdout.take().context("No pactl output")?).lines();
+    for line in lines {
+        // e.g. `Event 'change' on sink #56`, the server changes when the default sink is switched
+        let line = line?;
+        if line.contains(" on sink #") || line.contains(" on server") {
+            update();
+        }
+    }
+    anyhow::bail!("pactl subscribe exited with {}", subscription.0.wait()?)
+}
+
+fn volume() -> anyhow::Result<Message> {
+    Ok(Message::Volume {
+        percent: parse_volume(&pactl("get-sink-volume")?).context("No volume in pactl output")?,
+        muted: parse_muted(&pactl("get-sink-mute")?).context("No mute state in pactl output")?,
+    })
+}
+
+/// `pactl` with untranslated output, the event and mute lines are parsed
+fn pactl_command() -> Command {
+    let mut command = Command::new("pactl");
+    command.env("LC_ALL", "C");
+    command
+}
+
+fn pactl(command: &str) -> anyhow::Result<String> {
+    let output = pactl_command()
+        .args([command, DEFAULT_SINK])
+        .output()
+        .context("Failed to run pactl")?;
+    if !output.status.success() {
+        anyhow::bail!("pactl {command} exited with {}", output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Average of the channel percentages,
+/// e.g. `Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: ...`
+fn parse_volume(output: &str) -> Option<u8> {
+    let percents: Vec<u64> = output
+        .split_whitespace()
+        .filter_map(|token| token.strip_suffix('%')?.parse().ok())
+        .collect();
+    if percents.is_empty() {
+        return None;
+    }
+    let average = percents.iter().sum::<u64>() / percents.len() as u64;
+    Some(average.min(u8::MAX.into()) as u8)
+}
+
+/// `Mute: yes` or `Mute: no`
+fn parse_muted(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("Mute: ")? {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Watch the default output and send its state to the keyboards with `volume` enabled
+pub fn spawn<T: HidTransport + 'static>(connection: SharedConnection<T>) {
+    thread::spawn(move || {
+        let forwarder = StateForwarder::new(connection, |keeb_config| keeb_config.volume);
+        // moving a slider emits a burst of events, the forwarder drops the repeated volumes
+        let result = watch(&mut |volume| forwarder.send(volume));
+        if let Err(err) = result {
+            tracing::error!("Volume watcher stopped: {err:#}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_channel_volumes() {
+        let output = "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 39322 /  60% / -13.31 dB\n        balance 0.18\n";
+        assert_eq!(parse_volume(output), Some(55));
+        assert_eq!(
+            parse_volume("Volume: mono: 98304 / 150% / 10.57 dB\n"),
+            Some(150)
+        );
+        assert_eq!(parse_volume(""), None);
+    }
+
+    #[test]
+    fn parses_mute_state() {
+        assert_eq!(parse_muted("Mute: yes\n"), Some(true));
+        assert_eq!(parse_muted("Mute: no\n"), Some(false));
+        assert_eq!(parse_muted("Stummschalten: ja\n"), None);
+    }
+}
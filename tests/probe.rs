@@ -0,0 +1,308 @@
+//! Probe flow against the in-memory transport, asserting the exact reports the boards get
+
+use std::{ffi::CString, thread, time::Duration};
+
+use keeb_os_probe::{
+    events::BoardEvent,
+    protocol::{self, command, Message},
+    registry::UsbLocation,
+    transport::{DeviceInfo, MockTransport, MOCK_KEYBOARD, MOCK_USB},
+    BoardConnection, Config, SharedConnection,
+};
+
+/// The mock keyboard on a Linux host, followed by `keeb_config`
+fn klor(keeb_config: &str) -> String {
+    format!("[host]\nos = \"linux\"\n\n{MOCK_KEYBOARD}{keeb_config}")
+}
+
+fn connection(config: &str, transport: &MockTransport) -> BoardConnection<MockTransport> {
+    let config: Config = toml::from_str(config).unwrap();
+    config.validate().unwrap();
+    BoardConnection::with_transport(transport.clone(), config)
+}
+
+/// Report ID followed by the zero padded data
+fn report(data: &[u8]) -> Vec<u8> {
+    let mut report = vec![0; protocol::PACKET_SIZE + 1];
+    report[1..=data.len()].copy_from_slice(data);
+    report
+}
+
+#[test]
+fn default_host_report_is_the_legacy_one() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let mut connection = connection(&klor(""), &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    let written = transport.written();
+    assert_eq!(written.len(), 1);
+    // [report ID, 42, os_code = linux]
+    assert_eq!(written[0].data, report(&[command::HOST_OS, 1]));
+}
+
+#[test]
+fn framed_host_report_carries_the_header() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let mut connection = connection(&klor("protocol = \"framed\"\n"), &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    let expected = protocol::encode(&Message::HostOs {
+        os_code: 1,
+        profile_id: 0,
+    })
+    .unwrap();
+    assert_eq!(transport.written()[0].data, report(&expected[0]));
+}
+
+#[test]
+fn probe_registers_the_board() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let connection = SharedConnection::new(connection(&klor(""), &transport));
+    connection.probe_with_retry("klor", MOCK_USB);
+    let boards = connection.lock().registry().boards().to_vec();
+    assert_eq!(boards.len(), 1);
+    assert_eq!((boards[0].keeb.as_str(), boards[0].usb), ("klor", MOCK_USB));
+    assert_eq!(boards[0].paths, ["mock-0"]);
+    assert_eq!(transport.written().len(), 1);
+}
+
+#[test]
+fn host_info_follows_the_host_os() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let config = r#"
+[host]
+os = "linux"
+distro_id = "fedora"
+desktop = "GNOME"
+hostname = "work-laptop"
+
+[keyboards.klor]
+vendor_id = 0x3a3c
+product_id = 0x0001
+host_info = true
+"#;
+    let mut connection = connection(config, &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    let mut expected = vec![report(&[command::HOST_OS, 1])];
+    expected.extend(
+        protocol::encode(&Message::host_info("fedora", "GNOME", "work-laptop"))
+            .unwrap()
+            .iter()
+            .map(|packet| report(packet)),
+    );
+    let written: Vec<_> = transport
+        .written()
+        .into_iter()
+        .map(|report| report.data)
+        .collect();
+    assert_eq!(written, expected);
+}
+
+#[test]
+fn unplugged_board_gets_nothing() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    transport.detach(&CString::new("mock-0").unwrap());
+    let mut connection = connection(&klor(""), &transport);
+    assert!(!connection.probe_keeb("klor").unwrap());
+    assert!(transport.written().is_empty());
+}
+
+const HANDSHAKE: &str = "protocol = \"framed\"\nhandshake = { timeout_ms = 50, retries = 2 }\n";
+
+fn push_ack(transport: &MockTransport, value: u8) {
+    let ack = Message::Ack {
+        command: command::HOST_OS,
+        value,
+    };
+    for packet in protocol::encode(&ack).unwrap() {
+        transport.push_input(&CString::new("mock-0").unwrap(), &packet);
+    }
+}
+
+#[test]
+fn acked_host_report_is_sent_once() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    push_ack(&transport, 1);
+    let mut connection = connection(&klor(HANDSHAKE), &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    assert_eq!(transport.written().len(), 1);
+}
+
+#[test]
+fn mismatched_ack_keeps_the_board() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    // acked as Windows
+    push_ack(&transport, 2);
+    let mut connection = connection(&klor(HANDSHAKE), &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    assert_eq!(transport.written().len(), 1);
+}
+
+#[test]
+fn missing_ack_resends_the_host_report() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let mut connection = connection(&klor(HANDSHAKE), &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    let written = transport.written();
+    // the first write and 2 retries
+    assert_eq!(written.len(), 3);
+    assert!(written.windows(2).all(|pair| pair[0].data == pair[1].data));
+}
+
+#[test]
+fn profile_default_layer_follows_the_host_report() {
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo::mock("mock-0"));
+    let config = klor("protocol = \"framed\"\n").replace(
+        "os = \"linux\"",
+        "os = \"linux\"\nhostname = \"work-laptop\"\n\n[profiles.Work-Laptop]\nprofile_id = 3\ndefault_layer = 2",
+    );
+    let mut connection = connection(&config, &transport);
+    assert!(connection.probe_keeb("klor").unwrap());
+    let written = transport.written();
+    assert_eq!(written.len(), 2);
+    let host_os = protocol::encode(&Message::HostOs {
+        os_code: 1,
+        profile_id: 3,
+    })
+    .unwrap();
+    assert_eq!(written[0].data, report(&host_os[0]));
+    let set_layer = protocol::encode(&Message::SetLayer { layer: 2 }).unwrap();
+    assert_eq!(written[1].data, report(&set_layer[0]));
+}
+
+#[test]
+fn waits_for_the_hid_node_after_the_usb_arrival() {
+    let transport = MockTransport::new();
+    let connection = SharedConnection::new(connection(
+        &klor("\n[retry]\nready_timeout_ms = 2000\n"),
+        &transport,
+    ));
+    let late_transport = transport.clone();
+    let late = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        late_transport.attach(DeviceInfo::mock("mock-0"));
+    });
+    connection.probe_with_retry("klor", MOCK_USB);
+    late.join().unwrap();
+    assert_eq!(connection.lock().registry().boards().len(), 1);
+    assert_eq!(transport.written()[0].data, report(&[command::HOST_OS, 1]));
+}
+
+#[test]
+fn gives_up_on_a_node_that_never_opens() {
+    let transport = MockTransport::new();
+    let device = DeviceInfo::mock("mock-0");
+    transport.attach(device.clone());
+    transport.fail_opens(&device.path);
+    let connection = SharedConnection::new(connection(
+        &klor("\n[retry]\nattempts = 3\nbackoff_ms = 1\nmax_backoff_ms = 2\n"),
+        &transport,
+    ));
+    connection.probe_with_retry("klor", MOCK_USB);
+    assert_eq!(transport.open_attempts(&device.path), 3);
+    assert!(connection.lock().registry().boards().is_empty());
+    assert!(transport.written().is_empty());
+}
+
+#[test]
+fn identical_boards_stay_on_their_own_usb_device() {
+    const USB_B: UsbLocation = UsbLocation { bus: 1, address: 8 };
+    let transport = MockTransport::new();
+    transport.attach(DeviceInfo {
+        serial_number: Some("A".to_owned()),
+        ..DeviceInfo::mock("mock-a")
+    });
+    transport.attach(DeviceInfo {
+        usb: Some(USB_B),
+        serial_number: Some("B".to_owned()),
+        ..DeviceInfo::mock("mock-b")
+    });
+    let config = r#"
+[host]
+os = "linux"
+
+[retry]
+ready_timeout_ms = 20
+
+[keyboards.klor_a]
+vendor_id = 0x3a3c
+product_id = 0x0001
+serial_number = "A"
+
+[keyboards.klor_b]
+vendor_id = 0x3a3c
+product_id = 0x0001
+serial_number = "B"
+"#;
+    let connection = SharedConnection::new(connection(config, &transport));
+    // both configs match the IDs of the arriving USB device
+    let keebs = connection.lock().keebs_matching(0x3a3c, 0x0001);
+    assert_eq!(keebs.len(), 2);
+    for keeb in keebs {
+        connection.probe_with_retry(&keeb, MOCK_USB);
+    }
+    let boards = connection.lock().registry().boards().to_vec();
+    assert_eq!(boards.len(), 1);
+    assert_eq!(boards[0].keeb, "klor_a");
+    assert_eq!(boards[0].paths, ["mock-a"]);
+    assert!(transport
+        .written()
+        .iter()
+        .all(|written| written.path.as_c_str() == c"mock-a"));
+
+    connection.probe_with_retry("klor_b", USB_B);
+    connection.lock().device_left(USB_B);
+    let boards = connection.lock().registry().boards().to_vec();
+    assert_eq!(boards.len(), 1);
+    assert_eq!(boards[0].keeb, "klor_a");
+}
+
+#[test]
+fn reload_keeps_the_sessions_of_unchanged_boards() {
+    let transport = MockTransport::new();
+    let device = DeviceInfo::mock("mock-0");
+    transport.attach(device.clone());
+    let connection = SharedConnection::new(connection(&klor(""), &transport));
+    connection.probe_with_retry("klor", MOCK_USB);
+    connection
+        .lock()
+        .set_config(toml::from_str(&klor("stats = true\n")).unwrap());
+    assert_eq!(transport.open_attempts(&device.path), 1);
+    assert!(connection.lock().send_raw("klor", &[42, 1]).unwrap());
+    assert_eq!(transport.open_attempts(&device.path), 1);
+}
+
+#[test]
+fn reload_reopens_boards_with_changed_reports() {
+    let transport = MockTransport::new();
+    let device = DeviceInfo::mock("mock-0");
+    transport.attach(device.clone());
+    let connection = SharedConnection::new(connection(&klor(""), &transport));
+    connection.probe_with_retry("klor", MOCK_USB);
+    let events = connection.lock().events().subscribe();
+    connection
+        .lock()
+        .set_config(toml::from_str(&klor("report_size = 64\n")).unwrap());
+    assert_eq!(transport.open_attempts(&device.path), 2);
+
+    // the reopened reader picks up board messages without another write
+    let action = Message::Action {
+        name: "lock".to_owned(),
+    };
+    transport.push_input(&device.path, &protocol::encode(&action).unwrap()[0]);
+    let event = events.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(
+        event,
+        BoardEvent::Message {
+            keeb: "klor".to_owned(),
+            message: action,
+        }
+    );
+}